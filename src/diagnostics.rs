@@ -0,0 +1,258 @@
+use std::fmt::Write;
+
+use crate::lexer::Span;
+
+/// A named source text with its line starts, for mapping byte offsets to lines and columns.
+pub struct SourceFile {
+    name: String,
+    src: String,
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    pub fn new(name: impl Into<String>, src: impl Into<String>) -> SourceFile {
+        let src = src.into();
+        let line_starts = std::iter::once(0)
+            .chain(src.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        SourceFile {
+            name: name.into(),
+            src,
+            line_starts,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+
+    /// Zero-based index of the line containing `offset`.
+    pub fn line_index(&self, offset: usize) -> usize {
+        match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        }
+    }
+
+    /// One-based line and column (in characters) of `offset`.
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.src.len());
+        let line = self.line_index(offset);
+        let col = self.src[self.line_starts[line]..offset].chars().count();
+        (line + 1, col + 1)
+    }
+
+    /// The text of the zero-based line `line`, without its line ending.
+    pub fn line(&self, line: usize) -> &str {
+        let lo = self.line_starts[line];
+        let hi = self
+            .line_starts
+            .get(line + 1)
+            .copied()
+            .unwrap_or(self.src.len());
+        self.src[lo..hi].trim_end_matches(&['\n', '\r'][..])
+    }
+
+    pub fn snippet(&self, span: Span) -> &str {
+        &self.src[span.lo.min(self.src.len())..span.hi.min(self.src.len())]
+    }
+
+    /// An empty span at the very end of the file, used for end-of-input errors.
+    pub fn eof_span(&self) -> Span {
+        Span {
+            lo: self.src.len(),
+            hi: self.src.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+            Level::Note => "note",
+        }
+    }
+}
+
+/// A message about the source, optionally pointing at a span of it.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    pub span: Option<Span>,
+    pub label: Option<String>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(level: Level, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            level,
+            message: message.into(),
+            span: None,
+            label: None,
+            notes: vec![],
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Level::Error, message)
+    }
+
+    pub fn with_span(mut self, span: Span) -> Diagnostic {
+        self.span = Some(span);
+        self
+    }
+
+    /// Text printed next to the underline.
+    pub fn with_label(mut self, label: impl Into<String>) -> Diagnostic {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Renders the diagnostic in the usual `file:line:col` form, with the
+    /// offending line and the span underlined.
+    pub fn render(&self, file: &SourceFile) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "{}: {}", self.level.as_str(), self.message);
+
+        if let Some(span) = self.span {
+            let (line, col) = file.line_col(span.lo);
+            let gutter = " ".repeat(line.to_string().len());
+            let _ = writeln!(out, "{}--> {}:{}:{}", gutter, file.name(), line, col);
+            let _ = writeln!(out, "{} |", gutter);
+
+            let text = file.line(line - 1);
+            let line_lo = file.line_starts[line - 1];
+            let start = (span.lo - line_lo).min(text.len());
+            // only the first line of a multi-line span is underlined
+            let end = (span.hi.max(span.lo) - line_lo).min(text.len()).max(start);
+            let pad = display_width(&text[..start]);
+            let carets = display_width(&text[start..end]).max(1);
+
+            let _ = writeln!(out, "{} | {}", line, text.replace('\t', "    "));
+            let _ = write!(
+                out,
+                "{} | {}{}",
+                gutter,
+                " ".repeat(pad),
+                "^".repeat(carets)
+            );
+            match &self.label {
+                Some(label) => {
+                    let _ = writeln!(out, " {}", label);
+                }
+                None => {
+                    let _ = writeln!(out);
+                }
+            }
+            for note in &self.notes {
+                let _ = writeln!(out, "{} = note: {}", gutter, note);
+            }
+        } else {
+            for note in &self.notes {
+                let _ = writeln!(out, "  = note: {}", note);
+            }
+        }
+        out
+    }
+}
+
+fn display_width(s: &str) -> usize {
+    s.chars().map(|c| if c == '\t' { 4 } else { 1 }).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(lo: usize, hi: usize) -> Span {
+        Span { lo, hi }
+    }
+
+    #[test]
+    fn line_col_counts_characters() {
+        let file = SourceFile::new("a.sug", "ab\nçd\n\nx");
+        assert_eq!(file.line_col(0), (1, 1));
+        assert_eq!(file.line_col(3), (2, 1));
+        // `ç` is two bytes but one column
+        assert_eq!(file.line_col(5), (2, 2));
+        assert_eq!(file.line_col(7), (3, 1));
+        assert_eq!(file.line_col(100), (4, 2));
+        assert_eq!(file.line(1), "çd");
+    }
+
+    #[test]
+    fn renders_a_span_with_its_label_and_notes() {
+        let file = SourceFile::new("a.sug", "declare main = i32;\ndefine main = x;\n");
+        let diagnostic = Diagnostic::error("cannot find variable `x`")
+            .with_span(span(34, 35))
+            .with_label("not found in this function")
+            .with_note("declare it with `var`");
+        assert_eq!(
+            diagnostic.render(&file),
+            "error: cannot find variable `x`\n \
+             --> a.sug:2:15\n  \
+             |\n\
+             2 | define main = x;\n  \
+             |               ^ not found in this function\n  \
+             = note: declare it with `var`\n"
+        );
+    }
+
+    #[test]
+    fn underlines_the_first_line_of_a_long_span_after_tabs() {
+        let file = SourceFile::new("a.sug", "{\n\tvar x = 1;\n}");
+        let diagnostic = Diagnostic::error("e").with_span(span(3, 14));
+        assert_eq!(
+            diagnostic.render(&file),
+            "error: e\n \
+             --> a.sug:2:2\n  \
+             |\n\
+             2 |     var x = 1;\n  \
+             |     ^^^^^^^^^^\n"
+        );
+    }
+
+    #[test]
+    fn gutter_grows_with_the_line_number() {
+        let src = "\n".repeat(11) + "x";
+        let file = SourceFile::new("a.sug", src);
+        let rendered = Diagnostic::error("e")
+            .with_span(file.eof_span())
+            .render(&file);
+        assert_eq!(
+            rendered,
+            "error: e\n  --> a.sug:12:2\n   |\n12 | x\n   |  ^\n"
+        );
+    }
+
+    #[test]
+    fn renders_notes_without_a_span() {
+        let rendered = Diagnostic::error("`main` function not found")
+            .with_note("declare it")
+            .render(&SourceFile::new("a.sug", ""));
+        assert_eq!(
+            rendered,
+            "error: `main` function not found\n  = note: declare it\n"
+        );
+    }
+}
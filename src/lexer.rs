@@ -1,5 +1,9 @@
+use std::num::IntErrorKind;
+
 use plex::lexer;
 
+use crate::types::IntType;
+
 #[derive(Debug, Clone)]
 pub enum Token {
     Ident(String),
@@ -9,9 +13,14 @@ pub enum Token {
 
     Define,
     Declare,
+    Extern,
     Return,
 
-    Byte(u8),
+    Int(u128, Option<IntType>),
+    /// A string literal, without its quotes.
+    Str(String),
+    True,
+    False,
 
     Bang,
 
@@ -38,6 +47,7 @@ pub enum Token {
 
     SemiColon,
     Colon,
+    Comma,
 
     Whitespace,
     Comment,
@@ -45,8 +55,28 @@ pub enum Token {
     If,
     Else,
     For,
+    While,
+    Loop,
+    Break,
+    Continue,
+    /// A loop label like `'outer`, without its quote.
+    Label(String),
 
     Pass,
+
+    Error(LexError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LexError {
+    /// A character no other token starts with.
+    UnknownCharacter,
+    /// An integer literal without any digits, e.g. `0x_`.
+    InvalidInt,
+    /// An integer suffix that is not an integer type, e.g. `7u7`.
+    InvalidSuffix,
+    /// An integer literal that does not fit in 128 bits.
+    IntTooLarge,
 }
 
 lexer! {
@@ -62,21 +92,36 @@ lexer! {
     r#"var"# => Token::Variable,
     r#"mutate"# => Token::Mutate,
 
-    r#"[0-9]+"# => Token::Byte(text.parse().unwrap()),
+    r#"[0-9][0-9_]*([iu][0-9]+)?"# => int_literal(text, 10),
+    r#"0x[0-9a-fA-F_]+([iu][0-9]+)?"# => int_literal(&text[2..], 16),
+    r#"0o[0-7_]+([iu][0-9]+)?"# => int_literal(&text[2..], 8),
+    r#"0b[01_]+([iu][0-9]+)?"# => int_literal(&text[2..], 2),
 
     r#"declare"# => Token::Declare,
     r#"define"# => Token::Define,
+    r#"extern"# => Token::Extern,
     r#"return"# => Token::Return,
 
     r#"if"# => Token::If,
     r#"else"# => Token::Else,
 
     r#"for"# => Token::For,
+    r#"while"# => Token::While,
+    r#"loop"# => Token::Loop,
+    r#"break"# => Token::Break,
+    r#"continue"# => Token::Continue,
 
     r#"pass"# => Token::Pass,
 
+    r#"true"# => Token::True,
+    r#"false"# => Token::False,
+
     r#"[a-zA-Z_][a-zA-Z0-9_]*"# => Token::Ident(text.to_owned()),
 
+    r#"'[a-zA-Z_][a-zA-Z0-9_]*"# => Token::Label(text[1..].to_owned()),
+
+    r#""[^"\n]*""# => Token::Str(text[1..text.len() - 1].to_owned()),
+
     r#"="# => Token::Equals,
     r#"\+"# => Token::Plus,
     r#"-"# => Token::Minus,
@@ -99,10 +144,75 @@ lexer! {
 
     r#"\;"# => Token::SemiColon,
     r#"\:"# => Token::Colon,
+    r#","# => Token::Comma,
 
     r#"!"# => Token::Bang,
 }
 
+/// How a token named as in its `Debug` output (e.g. `LBrace`) is written in the
+/// source, for error messages.
+pub fn describe_token(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "Ident" => "identifier",
+        "Variable" => "`var`",
+        "Mutate" => "`mutate`",
+        "Define" => "`define`",
+        "Declare" => "`declare`",
+        "Extern" => "`extern`",
+        "Return" => "`return`",
+        "Int" => "integer literal",
+        "Str" => "string literal",
+        "True" => "`true`",
+        "False" => "`false`",
+        "Bang" => "`!`",
+        "Equals" => "`=`",
+        "Plus" => "`+`",
+        "Minus" => "`-`",
+        "Star" => "`*`",
+        "Slash" => "`/`",
+        "Ampersand" => "`&`",
+        "Pipe" => "`|`",
+        "Percent" => "`%`",
+        "Gt" => "`>`",
+        "Lt" => "`<`",
+        "LParen" => "`(`",
+        "RParen" => "`)`",
+        "LBrace" => "`{`",
+        "RBrace" => "`}`",
+        "LBracket" => "`[`",
+        "RBracket" => "`]`",
+        "SemiColon" => "`;`",
+        "Colon" => "`:`",
+        "Comma" => "`,`",
+        "If" => "`if`",
+        "Else" => "`else`",
+        "For" => "`for`",
+        "While" => "`while`",
+        "Loop" => "`loop`",
+        "Break" => "`break`",
+        "Continue" => "`continue`",
+        "Label" => "loop label",
+        "Pass" => "`pass`",
+        _ => return None,
+    })
+}
+
+/// Splits the type suffix off an integer literal and parses its digits.
+fn int_literal(text: &str, radix: u32) -> Token {
+    let (digits, suffix) = match text.find(['i', 'u']) {
+        Some(at) => match IntType::from_name(&text[at..]) {
+            Some(ty) => (&text[..at], Some(ty)),
+            None => return Token::Error(LexError::InvalidSuffix),
+        },
+        None => (text, None),
+    };
+    match u128::from_str_radix(&digits.replace('_', ""), radix) {
+        Ok(value) => Token::Int(value, suffix),
+        Err(e) if *e.kind() == IntErrorKind::PosOverflow => Token::Error(LexError::IntTooLarge),
+        Err(_) => Token::Error(LexError::InvalidInt),
+    }
+}
+
 pub struct Lexer<'a> {
     original: &'a str,
     remaining: &'a str,
@@ -133,7 +243,17 @@ impl<'a> Iterator for Lexer<'a> {
                 self.remaining = new_remaining;
                 (tok, Span { lo, hi })
             } else {
-                return None;
+                // skip the offending character so the rest of the file is still lexed
+                let c = self.remaining.chars().next()?;
+                let lo = self.original.len() - self.remaining.len();
+                self.remaining = &self.remaining[c.len_utf8()..];
+                (
+                    Token::Error(LexError::UnknownCharacter),
+                    Span {
+                        lo,
+                        hi: lo + c.len_utf8(),
+                    },
+                )
             };
             match tok {
                 Token::Whitespace | Token::Comment => {
@@ -146,3 +266,96 @@ impl<'a> Iterator for Lexer<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(src: &str) -> Vec<Token> {
+        Lexer::new(src).map(|(tok, _)| tok).collect()
+    }
+
+    fn int(src: &str) -> Token {
+        match lex(src).as_slice() {
+            [tok] => tok.clone(),
+            toks => panic!("`{}` is not one token: {:?}", src, toks),
+        }
+    }
+
+    #[test]
+    fn token_descriptions() {
+        assert_eq!(describe_token("LBrace"), Some("`{`"));
+        assert_eq!(describe_token("Ident"), Some("identifier"));
+        assert_eq!(describe_token("Whitespace"), None);
+    }
+
+    #[test]
+    fn unknown_characters_are_skipped() {
+        let toks = lex("a $ b");
+        assert!(matches!(
+            toks.as_slice(),
+            [
+                Token::Ident(_),
+                Token::Error(LexError::UnknownCharacter),
+                Token::Ident(_)
+            ]
+        ));
+    }
+
+    #[test]
+    fn int_literals_in_every_radix() {
+        assert!(matches!(int("255"), Token::Int(255, None)));
+        assert!(matches!(int("1_000"), Token::Int(1000, None)));
+        assert!(matches!(int("0xff"), Token::Int(255, None)));
+        assert!(matches!(int("0xFF_FF"), Token::Int(65535, None)));
+        assert!(matches!(int("0o17"), Token::Int(15, None)));
+        assert!(matches!(int("0b1010"), Token::Int(10, None)));
+    }
+
+    #[test]
+    fn int_literal_suffixes() {
+        assert!(matches!(int("7i32"), Token::Int(7, Some(IntType::I32))));
+        assert!(matches!(int("0xffu8"), Token::Int(255, Some(IntType::U8))));
+        assert!(matches!(int("0b1_i64"), Token::Int(1, Some(IntType::I64))));
+        assert!(matches!(int("7u7"), Token::Error(LexError::InvalidSuffix)));
+    }
+
+    #[test]
+    fn malformed_int_literals() {
+        assert!(matches!(int("0x_"), Token::Error(LexError::InvalidInt)));
+        assert!(matches!(
+            int(&u128::MAX.to_string()),
+            Token::Int(u128::MAX, None)
+        ));
+        assert!(matches!(
+            int("340282366920938463463374607431768211456"),
+            Token::Error(LexError::IntTooLarge)
+        ));
+    }
+
+    #[test]
+    fn extern_and_strings() {
+        let toks = lex(r#"extern "C" externs"#);
+        assert!(matches!(
+            toks.as_slice(),
+            [Token::Extern, Token::Str(abi), Token::Ident(ident)] if abi == "C" && ident == "externs"
+        ));
+    }
+
+    #[test]
+    fn loop_keywords_and_labels() {
+        let toks = lex("'outer: while loop break continue whiles");
+        assert!(matches!(
+            toks.as_slice(),
+            [
+                Token::Label(label),
+                Token::Colon,
+                Token::While,
+                Token::Loop,
+                Token::Break,
+                Token::Continue,
+                Token::Ident(ident),
+            ] if label == "outer" && ident == "whiles"
+        ));
+    }
+}
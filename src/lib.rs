@@ -0,0 +1,14 @@
+//! The Sugar compiler as a library.
+//!
+//! The `sugar-lang` binary is a thin front-end over [`session::Session`], which
+//! drives the lexer, parser and LLVM backend for a single source file.
+
+pub mod diagnostics;
+pub mod lexer;
+pub mod linker;
+pub mod llvm;
+pub mod parser;
+pub mod repl;
+pub mod session;
+pub mod typeck;
+pub mod types;
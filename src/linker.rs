@@ -0,0 +1,392 @@
+//! Turning object files into executables with whatever linker is installed.
+//!
+//! A C compiler driver (`cc` or `clang`) is preferred, since it already knows
+//! where the C runtime's start files and libc live. Without one, `ld.lld` is
+//! run directly with those paths looked up in the usual library directories.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::session::{CompileError, Options};
+
+/// The linkers tried, in order, when `--linker` is not given.
+const DEFAULT_LINKERS: [&str; 3] = ["cc", "clang", "ld.lld"];
+
+/// The linkers tried for another target, which can be told what it is.
+const CROSS_LINKERS: [&str; 2] = ["clang", "ld.lld"];
+
+/// How a linker is invoked, which decides the arguments it is given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flavor {
+    /// A C compiler driver like `cc` or `gcc`, which adds the C runtime itself.
+    Cc,
+    /// `clang`, which is also told the target triple.
+    Clang,
+    /// `ld.lld` or another `ld`, which needs the C runtime spelled out.
+    Ld,
+    /// `wasm-ld`, for WebAssembly, where there is no C runtime.
+    WasmLd,
+}
+
+impl Flavor {
+    /// Guesses the flavor of a linker from its file name.
+    fn of(program: &Path) -> Flavor {
+        let name = program
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if name.contains("wasm-ld") {
+            Flavor::WasmLd
+        } else if name.contains("clang") {
+            Flavor::Clang
+        } else if name == "ld" || name.starts_with("ld.") || name.ends_with("-ld") {
+            Flavor::Ld
+        } else {
+            Flavor::Cc
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Linker {
+    pub program: PathBuf,
+    pub flavor: Flavor,
+}
+
+impl Linker {
+    /// Finds the linker to use for `triple`: the one given with `--linker`,
+    /// `wasm-ld` for WebAssembly, or else the first of `cc`, `clang` and
+    /// `ld.lld` that is installed. The host's `cc` only links for the host, so
+    /// it is skipped when `cross` compiling.
+    pub fn find(linker: Option<&Path>, triple: &str, cross: bool) -> Result<Linker, CompileError> {
+        let candidates = match linker {
+            Some(linker) => vec![linker.to_path_buf()],
+            None if triple.starts_with("wasm") => vec![PathBuf::from("wasm-ld")],
+            None if cross => CROSS_LINKERS.iter().map(PathBuf::from).collect(),
+            None => DEFAULT_LINKERS.iter().map(PathBuf::from).collect(),
+        };
+        candidates
+            .iter()
+            .find_map(|candidate| find_program(candidate))
+            .map(|program| Linker {
+                flavor: Flavor::of(&program),
+                program,
+            })
+            .ok_or_else(|| CompileError::LinkerNotFound {
+                cross: cross.then(|| triple.to_owned()),
+                tried: candidates
+                    .iter()
+                    .map(|candidate| candidate.display().to_string())
+                    .collect(),
+            })
+    }
+
+    /// The command linking `object` into an executable at `output`.
+    pub fn command(
+        &self,
+        options: &Options,
+        triple: &str,
+        object: &Path,
+        output: &Path,
+    ) -> Result<Command, CompileError> {
+        let crt = match self.flavor {
+            Flavor::Ld => Some(CRuntime::find(triple)?),
+            _ => None,
+        };
+        self.command_with(options, triple, crt, object, output)
+    }
+
+    /// Like [`command`](Linker::command), with the C runtime `ld` is given.
+    fn command_with(
+        &self,
+        options: &Options,
+        triple: &str,
+        crt: Option<CRuntime>,
+        object: &Path,
+        output: &Path,
+    ) -> Result<Command, CompileError> {
+        let mut command = Command::new(&self.program);
+        let mut start_files = None;
+        match self.flavor {
+            Flavor::Cc => {}
+            Flavor::Clang => {
+                if options.target.is_some() {
+                    // also picks the target's C runtime and libc, if installed
+                    command.arg(format!("--target={}", triple));
+                }
+            }
+            Flavor::Ld => {
+                let crt = crt.expect("the C runtime is looked up for `ld`");
+                command
+                    .arg(crt.dir.join("crt1.o"))
+                    .arg(crt.dir.join("crti.o"));
+                if options.r#static {
+                    // libc's static archive needs GCC's runtime, which `cc` adds itself
+                    let gcc = crt.gcc_dir.as_ref().ok_or_else(|| {
+                        CompileError::Link(format!(
+                            "`ld` cannot link statically for `{}` without GCC's `crtbeginT.o`",
+                            triple
+                        ))
+                    })?;
+                    command.arg(gcc.join("crtbeginT.o"));
+                } else {
+                    command
+                        .arg("-dynamic-linker")
+                        .arg(crt.dynamic_linker(triple));
+                }
+                start_files = Some(crt);
+            }
+            Flavor::WasmLd => {
+                // there is no C runtime calling `main`, so it is exported instead
+                command.args(["--no-entry", "--export=main"]);
+            }
+        }
+        if options.r#static {
+            command.arg("-static");
+        }
+
+        command.arg(object);
+        for dir in &options.library_paths {
+            command.arg("-L").arg(dir);
+        }
+        for library in &options.libraries {
+            command.arg(format!("-l{}", library));
+        }
+        if let Some(crt) = start_files {
+            command.arg("-L").arg(&crt.dir);
+            match &crt.gcc_dir {
+                Some(gcc) if options.r#static => {
+                    command
+                        .arg("-L")
+                        .arg(gcc)
+                        .args(["--start-group", "-lgcc", "-lgcc_eh", "-lc", "--end-group"])
+                        .arg(gcc.join("crtend.o"));
+                }
+                _ => {
+                    command.arg("-lc");
+                }
+            }
+            command.arg(crt.dir.join("crtn.o"));
+        }
+        command.args(&options.link_args);
+        command.arg("-o").arg(output);
+        Ok(command)
+    }
+}
+
+/// Where the C runtime's start files and libc are installed for a target.
+struct CRuntime {
+    dir: PathBuf,
+    /// The newest GCC's runtime, which only static executables need.
+    gcc_dir: Option<PathBuf>,
+}
+
+impl CRuntime {
+    /// Looks for `crt1.o` in the library directories of `triple`'s architecture.
+    fn find(triple: &str) -> Result<CRuntime, CompileError> {
+        let arch = triple.split('-').next().unwrap_or_default();
+        let multiarch = format!("{}-linux-gnu", arch);
+        let gcc_dir = fs::read_dir(Path::new("/usr/lib/gcc").join(&multiarch))
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|dir| dir.join("crtbeginT.o").is_file())
+            .max_by_key(|dir| gcc_version(dir));
+        [
+            Path::new("/usr/lib").join(&multiarch),
+            Path::new("/lib").join(&multiarch),
+            PathBuf::from("/usr/lib64"),
+            PathBuf::from("/usr/lib"),
+        ]
+        .into_iter()
+        .find(|dir| dir.join("crt1.o").is_file())
+        .map(|dir| CRuntime { dir, gcc_dir })
+        .ok_or_else(|| {
+            CompileError::Link(format!(
+                "could not find the C runtime (`crt1.o`) for `{}`",
+                triple
+            ))
+        })
+    }
+
+    /// The dynamic loader executables for `triple` are run by.
+    fn dynamic_linker(&self, triple: &str) -> &'static str {
+        match triple.split('-').next() {
+            Some("x86_64") => "/lib64/ld-linux-x86-64.so.2",
+            Some("aarch64") => "/lib/ld-linux-aarch64.so.1",
+            Some("riscv64") => "/lib/ld-linux-riscv64-lp64d.so.1",
+            _ => "/lib/ld-linux.so.2",
+        }
+    }
+}
+
+/// The version a GCC directory like `/usr/lib/gcc/x86_64-linux-gnu/12.2` is named after.
+fn gcc_version(dir: &Path) -> Vec<u32> {
+    dir.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .split('.')
+        .map_while(|part| part.parse().ok())
+        .collect()
+}
+
+/// Resolves `program` against `PATH`, unless it is already a path.
+fn find_program(program: &Path) -> Option<PathBuf> {
+    if program.components().count() > 1 {
+        return program.is_file().then(|| program.to_path_buf());
+    }
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn linker(program: &str) -> Linker {
+        Linker {
+            program: PathBuf::from(program),
+            flavor: Flavor::of(Path::new(program)),
+        }
+    }
+
+    /// A C runtime in `/crt`, with GCC's in `/gcc` if `gcc`.
+    fn crt(gcc: bool) -> Option<CRuntime> {
+        Some(CRuntime {
+            dir: PathBuf::from("/crt"),
+            gcc_dir: gcc.then(|| PathBuf::from("/gcc")),
+        })
+    }
+
+    /// The arguments `linker` is run with to link `prog.o` into `prog`.
+    fn args(linker: &Linker, options: &Options, crt: Option<CRuntime>) -> Vec<String> {
+        let triple = "x86_64-pc-linux-gnu";
+        let command = linker
+            .command_with(options, triple, crt, Path::new("prog.o"), Path::new("prog"))
+            .unwrap();
+        command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn flavors_are_guessed_from_the_name() {
+        let flavor = |program| Flavor::of(Path::new(program));
+        assert_eq!(flavor("cc"), Flavor::Cc);
+        assert_eq!(flavor("/usr/bin/gcc"), Flavor::Cc);
+        assert_eq!(flavor("clang-14"), Flavor::Clang);
+        assert_eq!(flavor("ld"), Flavor::Ld);
+        assert_eq!(flavor("ld.lld"), Flavor::Ld);
+        assert_eq!(flavor("aarch64-linux-gnu-ld"), Flavor::Ld);
+        assert_eq!(flavor("wasm-ld"), Flavor::WasmLd);
+    }
+
+    #[test]
+    fn libraries_come_after_the_object() {
+        let options = Options {
+            r#static: true,
+            libraries: vec![String::from("m")],
+            library_paths: vec![PathBuf::from("/opt/lib")],
+            link_args: vec![String::from("--gc-sections")],
+            ..Options::default()
+        };
+        assert_eq!(
+            args(&linker("cc"), &options, None),
+            [
+                "-static",
+                "prog.o",
+                "-L",
+                "/opt/lib",
+                "-lm",
+                "--gc-sections",
+                "-o",
+                "prog"
+            ]
+        );
+    }
+
+    #[test]
+    fn clang_is_told_the_target() {
+        let mut options = Options::default();
+        assert_eq!(
+            args(&linker("clang"), &options, None),
+            ["prog.o", "-o", "prog"]
+        );
+        options.target = Some(String::from("aarch64-linux-gnu"));
+        assert_eq!(
+            args(&linker("clang"), &options, None)[0],
+            "--target=x86_64-pc-linux-gnu"
+        );
+    }
+
+    #[test]
+    fn ld_is_given_the_c_runtime() {
+        assert_eq!(
+            args(&linker("ld.lld"), &Options::default(), crt(false)),
+            [
+                "/crt/crt1.o",
+                "/crt/crti.o",
+                "-dynamic-linker",
+                "/lib64/ld-linux-x86-64.so.2",
+                "prog.o",
+                "-L",
+                "/crt",
+                "-lc",
+                "/crt/crtn.o",
+                "-o",
+                "prog"
+            ]
+        );
+    }
+
+    #[test]
+    fn static_ld_is_given_gcc_runtime() {
+        let options = Options {
+            r#static: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            args(&linker("ld"), &options, crt(true)),
+            [
+                "/crt/crt1.o",
+                "/crt/crti.o",
+                "/gcc/crtbeginT.o",
+                "-static",
+                "prog.o",
+                "-L",
+                "/crt",
+                "-L",
+                "/gcc",
+                "--start-group",
+                "-lgcc",
+                "-lgcc_eh",
+                "-lc",
+                "--end-group",
+                "/gcc/crtend.o",
+                "/crt/crtn.o",
+                "-o",
+                "prog"
+            ]
+        );
+        let without_gcc = linker("ld").command_with(
+            &options,
+            "x86_64-pc-linux-gnu",
+            crt(false),
+            Path::new("prog.o"),
+            Path::new("prog"),
+        );
+        assert!(matches!(without_gcc, Err(CompileError::Link(_))));
+    }
+
+    #[test]
+    fn wasm_exports_main() {
+        assert_eq!(
+            args(&linker("wasm-ld"), &Options::default(), None),
+            ["--no-entry", "--export=main", "prog.o", "-o", "prog"]
+        );
+    }
+}
@@ -1,399 +1,1327 @@
 use std::collections::HashMap;
-use std::env;
-use std::ffi::CString;
-use std::fs;
-use std::fs::File;
-use std::io::Read;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
 use std::path::Path;
-use std::process::exit;
+use std::sync::Once;
 
 use llvm_sys::analysis::*;
 use llvm_sys::bit_writer::*;
 use llvm_sys::core::*;
+use llvm_sys::debuginfo::*;
+use llvm_sys::error::*;
 use llvm_sys::execution_engine::*;
 use llvm_sys::prelude::*;
 use llvm_sys::target::*;
+use llvm_sys::target_machine::*;
+use llvm_sys::transforms::pass_builder::*;
 use llvm_sys::*;
 
+use crate::diagnostics::SourceFile;
+use crate::lexer::Span;
 use crate::parser::{Expr, Expr_};
+use crate::types::{is_untyped, IntType, Type};
 
-pub unsafe fn compile_llvm(ast: Vec<Expr>) {
+/// Stack slots of the variables in scope, with their types.
+type Variables = HashMap<String, (LLVMValueRef, Type)>;
+
+/// Every declared function, with its parameter and return types.
+type Functions = HashMap<String, (LLVMValueRef, Vec<Type>, Type)>;
+
+/// Held by tests using LLVM's global context, which is not thread safe.
+#[cfg(test)]
+pub(crate) static GLOBAL_CONTEXT: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Lowers the top-level declarations and definitions of a program into a new module.
+///
+/// With a `source`, the module also gets DWARF debug info pointing into it.
+///
+/// # Safety
+///
+/// Calls straight into the LLVM C API using the global context.
+pub unsafe fn compile_llvm(ast: Vec<Expr>, source: Option<&SourceFile>) -> Module {
     let module = LLVMModuleCreateWithName(b"main\0".as_ptr() as *const _);
+    let mut debug = source.map(|source| DebugInfo::new(module, source));
+
+    let mut functions: Functions = HashMap::new();
 
-    let mut functions = HashMap::new();
+    // declare every function up front, so a body can call functions declared after it
+    for x in &ast {
+        if let Expr_::Declare(name, types, abi) = &x.node {
+            let (return_type, params) = types.split_last().unwrap();
+            let params = params
+                .iter()
+                .filter(|t| **t != Type::Void)
+                .cloned()
+                .collect::<Vec<Type>>();
+            let mut args = params
+                .iter()
+                .map(|t| llvm_type(t))
+                .collect::<Vec<LLVMTypeRef>>();
+            let function_sig = LLVMFunctionType(
+                llvm_type(return_type),
+                args.as_mut_ptr(),
+                args.len() as u32,
+                0,
+            );
+
+            let name_c = CString::new(name.clone()).unwrap();
+            let func = LLVMAddFunction(module, name_c.as_ptr(), function_sig);
+            if abi.is_some() {
+                extend_c_arguments(func, &params, return_type);
+            }
+            functions.insert(name.clone(), (func, params, return_type.clone()));
+        }
+    }
 
     for x in ast {
         match x.node {
-            Expr_::Declare(name, types) => {
-                let types = types
-                    .into_iter()
-                    .map(|t| match t.as_str() {
-                        "byte" => LLVMInt8Type(),
-                        "void" => LLVMVoidType(),
-                        _ => panic!("Unknown Type"),
-                    })
-                    .collect::<Vec<LLVMTypeRef>>();
-                let return_type = types[types.len() - 1];
-                let args = &types[..types.len() - 2];
-                let function_sig =
-                    LLVMFunctionType(return_type, args.as_ptr() as *mut _, args.len() as u32, 0);
-
-                let name_c = CString::new(name.clone()).unwrap();
-                let func = LLVMAddFunction(module, name_c.as_ptr(), function_sig);
-                functions.insert(name.clone(), func);
-            }
-            Expr_::Define(name, _args, expr) => {
-                let func = *functions.get_key_value(&name).unwrap().1;
+            Expr_::Declare(..) => {}
+            Expr_::Define(name, args, expr) => {
+                let _span = tracing::debug_span!("function", %name).entered();
+                let (func, params, return_type) = functions.get(&name).cloned().unwrap();
                 let entry_name = CString::new("entry").unwrap();
                 let entry = LLVMAppendBasicBlock(func, entry_name.as_ptr());
                 let builder = LLVMCreateBuilder();
                 LLVMPositionBuilderAtEnd(builder, entry);
+                if let Some(debug) = &mut debug {
+                    debug.enter_function(func, &name, &params, &return_type, x.span);
+                    debug.set_location(builder, x.span);
+                }
                 let mut variables = HashMap::new();
-                if expr.len() == 1 {
-                    match expr[0].node {
-                        Expr_::Byte(v) => {
-                            LLVMBuildRet(builder, LLVMConstInt(LLVMInt8Type(), v.into(), 0));
-                        }
-                        _ => todo!(),
+                // spill the parameters so they can be reassigned like any variable
+                for (i, (arg, ty)) in args.into_iter().zip(params).enumerate() {
+                    let name_c = CString::new(arg.clone()).unwrap();
+                    let param = LLVMGetParam(func, i as u32);
+                    LLVMSetValueName2(param, name_c.as_ptr(), arg.len());
+                    let alloced = LLVMBuildAlloca(builder, llvm_type(&ty), name_c.as_ptr());
+                    LLVMBuildStore(builder, param, alloced);
+                    if let Some(debug) = &debug {
+                        debug.declare_variable(builder, alloced, &arg, &ty, x.span, Some(i + 1));
+                    }
+                    variables.insert(arg, (alloced, ty));
+                }
+                // a single-expression body is lowered as a `return` of that expression
+                iter_statements(
+                    builder,
+                    expr,
+                    &mut variables,
+                    &functions,
+                    &return_type,
+                    &mut vec![],
+                    debug.as_ref(),
+                );
+                if !terminated(builder) {
+                    if return_type == Type::Void {
+                        LLVMBuildRetVoid(builder);
+                    } else {
+                        // every path returned already, e.g. from both arms of an `if`
+                        LLVMBuildUnreachable(builder);
                     }
-                } else {
-                    iter_statements(builder, expr, &mut variables)
                 }
+                LLVMDisposeBuilder(builder);
+            }
+            _ => unreachable!("only declarations and definitions are parsed at the top level"),
+        }
+    }
+    if let Some(debug) = debug {
+        debug.finish();
+    }
+    Module { raw: module }
+}
+
+/// Marks the integers narrower than an `int` in the signature of a C function as
+/// zero or sign extended, as the C calling convention promotes them.
+unsafe fn extend_c_arguments(func: LLVMValueRef, params: &[Type], ret: &Type) {
+    // attribute index 0 is the return value, the parameters start at 1
+    for (index, ty) in std::iter::once(ret).chain(params).enumerate() {
+        let attribute = match ty {
+            Type::Int(int) if int.bits() < 32 && int.signed() => "signext",
+            Type::Int(int) if int.bits() < 32 => "zeroext",
+            Type::Bool => "zeroext",
+            _ => continue,
+        };
+        let kind = LLVMGetEnumAttributeKindForName(attribute.as_ptr() as *const _, attribute.len());
+        let attribute = LLVMCreateEnumAttribute(LLVMGetGlobalContext(), kind, 0);
+        LLVMAddAttributeAtIndex(func, index as u32, attribute);
+    }
+}
+
+/// Builds the DWARF debug info of a module: one compile unit for the source
+/// file, a subprogram for each function and a variable for each stack slot.
+struct DebugInfo<'a> {
+    builder: LLVMDIBuilderRef,
+    source: &'a SourceFile,
+    file: LLVMMetadataRef,
+    /// The subprogram of the function being lowered.
+    scope: LLVMMetadataRef,
+}
+
+impl<'a> DebugInfo<'a> {
+    unsafe fn new(module: LLVMModuleRef, source: &'a SourceFile) -> DebugInfo<'a> {
+        let version = LLVMValueAsMetadata(LLVMConstInt(
+            LLVMInt32Type(),
+            LLVMDebugMetadataVersion() as u64,
+            0,
+        ));
+        let key = "Debug Info Version";
+        LLVMAddModuleFlag(
+            module,
+            LLVMModuleFlagBehavior::LLVMModuleFlagBehaviorWarning,
+            key.as_ptr() as *const _,
+            key.len(),
+            version,
+        );
+
+        let builder = LLVMCreateDIBuilder(module);
+        let path = Path::new(source.name());
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| source.name().to_owned());
+        let dir = match path.parent() {
+            Some(dir) if dir.is_absolute() => dir.to_path_buf(),
+            dir => std::env::current_dir()
+                .unwrap_or_default()
+                .join(dir.unwrap_or_else(|| Path::new(""))),
+        };
+        let dir = dir.to_string_lossy();
+        let file = LLVMDIBuilderCreateFile(
+            builder,
+            name.as_ptr() as *const _,
+            name.len(),
+            dir.as_ptr() as *const _,
+            dir.len(),
+        );
+
+        let producer = concat!("sugar-lang ", env!("CARGO_PKG_VERSION"));
+        LLVMDIBuilderCreateCompileUnit(
+            builder,
+            LLVMDWARFSourceLanguage::LLVMDWARFSourceLanguageC,
+            file,
+            producer.as_ptr() as *const _,
+            producer.len(),
+            0,
+            std::ptr::null(),
+            0,
+            0,
+            std::ptr::null(),
+            0,
+            LLVMDWARFEmissionKind::LLVMDWARFEmissionKindFull,
+            0,
+            0,
+            0,
+            std::ptr::null(),
+            0,
+            std::ptr::null(),
+            0,
+        );
+        DebugInfo {
+            builder,
+            source,
+            file,
+            scope: file,
+        }
+    }
+
+    fn line_col(&self, span: Span) -> (u32, u32) {
+        let (line, col) = self.source.line_col(span.lo);
+        (line as u32, col as u32)
+    }
+
+    /// The DWARF type of `ty`, or null for `!`.
+    unsafe fn di_type(&self, ty: &Type) -> LLVMMetadataRef {
+        // DW_ATE_boolean, DW_ATE_signed and DW_ATE_unsigned
+        let (bits, encoding) = match ty {
+            Type::Int(int) if int.signed() => (int.bits(), 0x05),
+            Type::Int(int) => (int.bits(), 0x08),
+            Type::Bool => (8, 0x02),
+            Type::Void | Type::Named(_) => return std::ptr::null_mut(),
+        };
+        let name = ty.name();
+        LLVMDIBuilderCreateBasicType(
+            self.builder,
+            name.as_ptr() as *const _,
+            name.len(),
+            bits as u64,
+            encoding,
+            LLVMDIFlagZero,
+        )
+    }
+
+    /// Attaches a subprogram to `func`, making it the scope of what follows.
+    unsafe fn enter_function(
+        &mut self,
+        func: LLVMValueRef,
+        name: &str,
+        params: &[Type],
+        ret: &Type,
+        span: Span,
+    ) {
+        let mut types = std::iter::once(ret)
+            .chain(params)
+            .map(|ty| self.di_type(ty))
+            .collect::<Vec<_>>();
+        let signature = LLVMDIBuilderCreateSubroutineType(
+            self.builder,
+            self.file,
+            types.as_mut_ptr(),
+            types.len() as u32,
+            LLVMDIFlagZero,
+        );
+        let (line, _) = self.line_col(span);
+        self.scope = LLVMDIBuilderCreateFunction(
+            self.builder,
+            self.file,
+            name.as_ptr() as *const _,
+            name.len(),
+            name.as_ptr() as *const _,
+            name.len(),
+            self.file,
+            line,
+            signature,
+            0,
+            1,
+            line,
+            LLVMDIFlagZero,
+            0,
+        );
+        LLVMSetSubprogram(func, self.scope);
+    }
+
+    /// Attributes the instructions built from here on to `span`.
+    unsafe fn set_location(&self, builder: LLVMBuilderRef, span: Span) {
+        let (line, col) = self.line_col(span);
+        let location = LLVMDIBuilderCreateDebugLocation(
+            LLVMGetGlobalContext(),
+            line,
+            col,
+            self.scope,
+            std::ptr::null_mut(),
+        );
+        LLVMSetCurrentDebugLocation2(builder, location);
+    }
+
+    /// Describes the stack slot of a variable, or of the `arg`th parameter.
+    unsafe fn declare_variable(
+        &self,
+        builder: LLVMBuilderRef,
+        slot: LLVMValueRef,
+        name: &str,
+        ty: &Type,
+        span: Span,
+        arg: Option<usize>,
+    ) {
+        let (line, col) = self.line_col(span);
+        let ty = self.di_type(ty);
+        let variable = match arg {
+            Some(arg) => LLVMDIBuilderCreateParameterVariable(
+                self.builder,
+                self.scope,
+                name.as_ptr() as *const _,
+                name.len(),
+                arg as u32,
+                self.file,
+                line,
+                ty,
+                1,
+                LLVMDIFlagZero,
+            ),
+            None => LLVMDIBuilderCreateAutoVariable(
+                self.builder,
+                self.scope,
+                name.as_ptr() as *const _,
+                name.len(),
+                self.file,
+                line,
+                ty,
+                1,
+                LLVMDIFlagZero,
+                0,
+            ),
+        };
+        let location = LLVMDIBuilderCreateDebugLocation(
+            LLVMGetGlobalContext(),
+            line,
+            col,
+            self.scope,
+            std::ptr::null_mut(),
+        );
+        LLVMDIBuilderInsertDeclareAtEnd(
+            self.builder,
+            slot,
+            variable,
+            LLVMDIBuilderCreateExpression(self.builder, std::ptr::null_mut(), 0),
+            location,
+            LLVMGetInsertBlock(builder),
+        );
+    }
+
+    unsafe fn finish(self) {
+        LLVMDIBuilderFinalize(self.builder);
+        LLVMDisposeDIBuilder(self.builder);
+    }
+}
+
+/// An owned LLVM module, disposed when dropped.
+pub struct Module {
+    raw: LLVMModuleRef,
+}
+
+impl Module {
+    pub fn as_raw(&self) -> LLVMModuleRef {
+        self.raw
+    }
+
+    /// Renders the module as textual LLVM IR.
+    pub fn print_to_string(&self) -> String {
+        unsafe { take_message(LLVMPrintModuleToString(self.raw)) }
+    }
+
+    /// Checks that the module is well formed, returning LLVM's description of
+    /// what is wrong with it otherwise.
+    pub fn verify(&self) -> Result<(), String> {
+        unsafe {
+            let mut error = std::ptr::null_mut();
+            let broken = LLVMVerifyModule(
+                self.raw,
+                LLVMVerifierFailureAction::LLVMReturnStatusAction,
+                &mut error,
+            );
+            let message = take_message(error);
+            if broken != 0 {
+                return Err(message);
             }
-            _ => todo!(),
         }
+        Ok(())
+    }
+
+    /// Runs LLVM's default optimization pipeline for `level` over the module,
+    /// tuned for `machine`.
+    pub fn optimize(&self, machine: &TargetMachine, level: OptLevel) -> Result<(), String> {
+        if level == OptLevel::O0 {
+            return Ok(());
+        }
+        machine.configure(self);
+        let passes = CString::new(level.pipeline()).unwrap();
+        unsafe {
+            let options = LLVMCreatePassBuilderOptions();
+            let error = LLVMRunPasses(self.raw, passes.as_ptr(), machine.raw, options);
+            LLVMDisposePassBuilderOptions(options);
+            if !error.is_null() {
+                let message = LLVMGetErrorMessage(error);
+                let string = CStr::from_ptr(message).to_string_lossy().into_owned();
+                LLVMDisposeErrorMessage(message);
+                return Err(string);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the module as LLVM bitcode, returning `false` on failure.
+    pub fn write_bitcode(&self, path: &Path) -> bool {
+        let path = CString::new(path.to_string_lossy().as_bytes()).unwrap();
+        unsafe { LLVMWriteBitcodeToFile(self.raw, path.as_ptr()) == 0 }
     }
-    let mut error = std::ptr::null_mut() as *mut i8;
+}
+
+impl Drop for Module {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeModule(self.raw) }
+    }
+}
+
+/// Copies a message allocated by LLVM into a `String` and frees it.
+unsafe fn take_message(message: *mut c_char) -> String {
+    let string = CStr::from_ptr(message).to_string_lossy().into_owned();
+    LLVMDisposeMessage(message);
+    string
+}
+
+fn initialize_targets() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| unsafe {
+        LLVM_InitializeAllTargetInfos();
+        LLVM_InitializeAllTargets();
+        LLVM_InitializeAllTargetMCs();
+        LLVM_InitializeAllAsmParsers();
+        LLVM_InitializeAllAsmPrinters();
+    });
+}
+
+/// How hard LLVM optimizes, as chosen with `-O`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OptLevel {
+    #[default]
+    O0,
+    O1,
+    O2,
+    O3,
+    /// Like `O2`, but favouring smaller code.
+    Os,
+    /// Smaller code at any cost.
+    Oz,
+}
+
+impl OptLevel {
+    pub const ALL: [OptLevel; 6] = [
+        OptLevel::O0,
+        OptLevel::O1,
+        OptLevel::O2,
+        OptLevel::O3,
+        OptLevel::Os,
+        OptLevel::Oz,
+    ];
+
+    /// The level as written after `-O`.
+    pub fn name(self) -> &'static str {
+        match self {
+            OptLevel::O0 => "0",
+            OptLevel::O1 => "1",
+            OptLevel::O2 => "2",
+            OptLevel::O3 => "3",
+            OptLevel::Os => "s",
+            OptLevel::Oz => "z",
+        }
+    }
+
+    /// The new pass manager's pipeline for this level.
+    fn pipeline(self) -> String {
+        format!("default<O{}>", self.name())
+    }
+
+    fn codegen_level(self) -> LLVMCodeGenOptLevel {
+        match self {
+            OptLevel::O0 => LLVMCodeGenOptLevel::LLVMCodeGenLevelNone,
+            OptLevel::O1 => LLVMCodeGenOptLevel::LLVMCodeGenLevelLess,
+            OptLevel::O2 | OptLevel::Os | OptLevel::Oz => {
+                LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault
+            }
+            OptLevel::O3 => LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
+        }
+    }
+}
+
+impl std::str::FromStr for OptLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        OptLevel::ALL
+            .into_iter()
+            .find(|level| level.name() == s)
+            .ok_or_else(|| String::from("expected one of 0, 1, 2, 3, s or z"))
+    }
+}
+
+/// The kind of file [`TargetMachine::emit_to_file`] writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Object,
+    Assembly,
+}
+
+/// The triple of the machine the compiler runs on.
+pub fn host_triple() -> String {
+    unsafe { take_message(LLVMGetDefaultTargetTriple()) }
+}
+
+/// Fills in the parts of a triple left out, e.g. `aarch64-linux-gnu` becomes
+/// `aarch64-unknown-linux-gnu`.
+pub fn normalize_triple(triple: &str) -> String {
+    let triple = CString::new(triple).unwrap();
+    unsafe { take_message(LLVMNormalizeTargetTriple(triple.as_ptr())) }
+}
+
+/// The name and description of every target LLVM was built with.
+pub fn targets() -> Vec<(String, String)> {
+    initialize_targets();
+    let mut targets = vec![];
+    unsafe {
+        let mut target = LLVMGetFirstTarget();
+        while !target.is_null() {
+            targets.push((
+                CStr::from_ptr(LLVMGetTargetName(target))
+                    .to_string_lossy()
+                    .into_owned(),
+                CStr::from_ptr(LLVMGetTargetDescription(target))
+                    .to_string_lossy()
+                    .into_owned(),
+            ));
+            target = LLVMGetNextTarget(target);
+        }
+    }
+    targets
+}
+
+/// Generates machine code for one target, disposed when dropped.
+pub struct TargetMachine {
+    raw: LLVMTargetMachineRef,
+}
+
+impl TargetMachine {
+    /// A target machine for the host, tuned for its CPU.
+    pub fn host(level: OptLevel) -> Result<TargetMachine, String> {
+        unsafe {
+            let triple = host_triple();
+            let cpu = take_message(LLVMGetHostCPUName());
+            let features = take_message(LLVMGetHostCPUFeatures());
+            TargetMachine::new(&triple, &cpu, &features, level)
+        }
+    }
+
+    pub fn new(
+        triple: &str,
+        cpu: &str,
+        features: &str,
+        level: OptLevel,
+    ) -> Result<TargetMachine, String> {
+        initialize_targets();
+        let triple = CString::new(triple).unwrap();
+        let cpu = CString::new(cpu).unwrap();
+        let features = CString::new(features).unwrap();
+        unsafe {
+            let mut target = std::ptr::null_mut();
+            let mut error = std::ptr::null_mut();
+            if LLVMGetTargetFromTriple(triple.as_ptr(), &mut target, &mut error) != 0 {
+                return Err(take_message(error));
+            }
+            let raw = LLVMCreateTargetMachine(
+                target,
+                triple.as_ptr(),
+                cpu.as_ptr(),
+                features.as_ptr(),
+                level.codegen_level(),
+                LLVMRelocMode::LLVMRelocPIC,
+                LLVMCodeModel::LLVMCodeModelDefault,
+            );
+            if raw.is_null() {
+                return Err(format!(
+                    "could not create a target machine for `{}`",
+                    triple.to_string_lossy()
+                ));
+            }
+            Ok(TargetMachine { raw })
+        }
+    }
+
+    pub fn triple(&self) -> String {
+        unsafe { take_message(LLVMGetTargetMachineTriple(self.raw)) }
+    }
+
+    /// Sets the module's target triple and data layout to this machine's.
+    pub fn configure(&self, module: &Module) {
+        unsafe {
+            let triple = CString::new(self.triple()).unwrap();
+            LLVMSetTarget(module.raw, triple.as_ptr());
+            let layout = LLVMCreateTargetDataLayout(self.raw);
+            LLVMSetModuleDataLayout(module.raw, layout);
+            LLVMDisposeTargetData(layout);
+        }
+    }
+
+    /// Compiles `module` for this machine and writes the result to `path`.
+    pub fn emit_to_file(
+        &self,
+        module: &Module,
+        path: &Path,
+        file_type: FileType,
+    ) -> Result<(), String> {
+        self.configure(module);
+        let path = CString::new(path.to_string_lossy().as_bytes()).unwrap();
+        let file_type = match file_type {
+            FileType::Object => LLVMCodeGenFileType::LLVMObjectFile,
+            FileType::Assembly => LLVMCodeGenFileType::LLVMAssemblyFile,
+        };
+        unsafe {
+            let mut error = std::ptr::null_mut();
+            if LLVMTargetMachineEmitToFile(
+                self.raw,
+                module.raw,
+                path.as_ptr() as *mut _,
+                file_type,
+                &mut error,
+            ) != 0
+            {
+                return Err(take_message(error));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TargetMachine {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeTargetMachine(self.raw) }
+    }
+}
+
+/// Compiles a module in memory with MCJIT so its functions can be called
+/// directly, disposed (along with the module) when dropped.
+pub struct ExecutionEngine {
+    raw: LLVMExecutionEngineRef,
+}
 
-    let mut gag = gag::BufferRedirect::stderr().unwrap();
-    LLVMDumpModule(module);
+impl ExecutionEngine {
+    /// Takes ownership of `module`, which is compiled for the host.
+    pub fn new(module: Module) -> Result<ExecutionEngine, String> {
+        initialize_targets();
+        unsafe {
+            LLVMLinkInMCJIT();
+            let mut options = std::mem::zeroed::<LLVMMCJITCompilerOptions>();
+            let size = std::mem::size_of::<LLVMMCJITCompilerOptions>();
+            LLVMInitializeMCJITCompilerOptions(&mut options, size);
 
-    let mut output = String::new();
-    gag.read_to_string(&mut output).unwrap();
-    drop(gag);
-    let _ = File::create("./build/pre.ll");
+            let mut raw = std::ptr::null_mut();
+            let mut error = std::ptr::null_mut();
+            // the engine owns the module from here on, even if creating it fails
+            let module = std::mem::ManuallyDrop::new(module);
+            if LLVMCreateMCJITCompilerForModule(
+                &mut raw,
+                module.raw,
+                &mut options,
+                size,
+                &mut error,
+            ) != 0
+            {
+                return Err(take_message(error));
+            }
+            Ok(ExecutionEngine { raw })
+        }
+    }
 
-    fs::write("./build/pre.ll", output).expect("Unable to write file");
+    /// Takes ownership of another module, whose functions can call and be called
+    /// by those of the modules added before it.
+    pub fn add_module(&self, module: Module) {
+        let module = std::mem::ManuallyDrop::new(module);
+        unsafe { LLVMAddModule(self.raw, module.raw) }
+    }
 
-    LLVMVerifyModule(
-        module,
-        LLVMVerifierFailureAction::LLVMAbortProcessAction,
-        &mut error,
-    );
-    LLVMDisposeMessage(error);
+    /// The address of the compiled function `name`, compiling the modules if needed.
+    pub fn function_address(&self, name: &str) -> Option<u64> {
+        let name = CString::new(name).unwrap();
+        match unsafe { LLVMGetFunctionAddress(self.raw, name.as_ptr()) } {
+            0 => None,
+            address => Some(address),
+        }
+    }
 
-    let mut engine = std::ptr::null_mut();
-    let mut error = std::ptr::null_mut() as *mut i8;
+    /// Calls the function `name`, widening its result to an `i64`.
+    ///
+    /// # Safety
+    ///
+    /// `name` must take no arguments and return `ret`, and running it runs
+    /// whatever code the program contains.
+    pub unsafe fn call(&self, name: &str, ret: &Type) -> Result<i64, String> {
+        let address = self
+            .function_address(name)
+            .ok_or_else(|| format!("no function named `{}`", name))? as usize;
+        macro_rules! call_as {
+            ($ty:ty) => {
+                std::mem::transmute::<usize, extern "C" fn() -> $ty>(address)() as i64
+            };
+        }
+        Ok(match ret {
+            Type::Int(IntType::I8) => call_as!(i8),
+            Type::Int(IntType::I16) => call_as!(i16),
+            Type::Int(IntType::I32) => call_as!(i32),
+            Type::Int(IntType::I64) => call_as!(i64),
+            Type::Int(IntType::U8) => call_as!(u8),
+            Type::Int(IntType::U16) => call_as!(u16),
+            Type::Int(IntType::U32) => call_as!(u32),
+            Type::Int(IntType::U64) => call_as!(u64),
+            // an `i1` return leaves the upper bits of the register undefined,
+            // which is not a valid Rust `bool`
+            Type::Bool => (call_as!(u8) & 1) as i64,
+            Type::Void => {
+                std::mem::transmute::<usize, extern "C" fn()>(address)();
+                0
+            }
+            Type::Named(name) => {
+                return Err(format!("cannot call a function returning `{}`", name))
+            }
+        })
+    }
+}
 
-    LLVMLinkInMCJIT();
-    LLVM_InitializeAllTargetInfos();
-    LLVM_InitializeAllTargets();
-    LLVM_InitializeAllTargetMCs();
-    LLVM_InitializeAllAsmParsers();
-    LLVM_InitializeAllAsmPrinters();
+impl Drop for ExecutionEngine {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeExecutionEngine(self.raw) }
+    }
+}
 
-    if LLVMCreateExecutionEngineForModule(&mut engine, module, &mut error) == 1 {
-        eprintln!("failed to create execution engine");
-        exit(1);
+unsafe fn llvm_type(ty: &Type) -> LLVMTypeRef {
+    match ty {
+        Type::Int(ty) => LLVMIntType(ty.bits()),
+        Type::Bool => LLVMInt1Type(),
+        Type::Void => LLVMVoidType(),
+        Type::Named(name) => panic!("Unknown Type {}", name),
     }
+}
 
-    if error != std::ptr::null_mut() {
-        eprintln!("error: {:?}", error);
-        LLVMDisposeMessage(error);
-        exit(1);
+/// Lowers both operands of a binary operator. If only one of them is untyped it
+/// takes the type of the other, so `x + 1` works for any integer `x`.
+unsafe fn operands(
+    a: Expr,
+    b: Expr,
+    variables: &mut Variables,
+    functions: &Functions,
+    builder: LLVMBuilderRef,
+    hint: Option<&Type>,
+) -> (LLVMValueRef, LLVMValueRef, Type) {
+    if is_untyped(&a.node) && !is_untyped(&b.node) {
+        let (b, ty) = match_expr(b.node, variables, functions, builder, hint);
+        let (a, _) = match_expr(a.node, variables, functions, builder, Some(&ty));
+        (a, b, ty)
+    } else {
+        let (a, ty) = match_expr(a.node, variables, functions, builder, hint);
+        let (b, _) = match_expr(b.node, variables, functions, builder, Some(&ty));
+        (a, b, ty)
     }
+}
 
-    let builddir = Path::new("./build");
-    env::set_current_dir(&builddir).unwrap();
+unsafe fn compare(
+    builder: LLVMBuilderRef,
+    a: LLVMValueRef,
+    b: LLVMValueRef,
+    ty: &Type,
+    signed: LLVMIntPredicate,
+    unsigned: LLVMIntPredicate,
+) -> (LLVMValueRef, Type) {
+    let predicate = if ty.is_signed() { signed } else { unsigned };
+    (
+        LLVMBuildICmp(builder, predicate, a, b, b"tmp\0".as_ptr() as *const _),
+        Type::Bool,
+    )
+}
 
-    if LLVMWriteBitcodeToFile(module, b"out.bc\0".as_ptr() as *const _) != 0 {
-        eprintln!("error writing bitcode to file, skipping");
+/// Converts a value to an `i1` that is set when the value is not zero.
+unsafe fn truthy(builder: LLVMBuilderRef, value: LLVMValueRef, ty: &Type) -> LLVMValueRef {
+    match ty {
+        Type::Bool => value,
+        ty => LLVMBuildICmp(
+            builder,
+            LLVMIntPredicate::LLVMIntNE,
+            value,
+            LLVMConstInt(llvm_type(ty), 0, 0),
+            b"tmp\0".as_ptr() as *const _,
+        ),
     }
 }
 
+/// Lowers an expression, returning its value and type. `hint` is the type the
+/// surrounding code expects, which unsuffixed literals adopt.
 unsafe fn match_expr(
     expr: Expr_,
-    mut variables: &mut HashMap<String, LLVMValueRef>,
+    variables: &mut Variables,
+    functions: &Functions,
     builder: LLVMBuilderRef,
-) -> LLVMValueRef {
+    hint: Option<&Type>,
+) -> (LLVMValueRef, Type) {
     match expr {
-        Expr_::Byte(v) => LLVMConstInt(LLVMInt8Type(), v.into(), 0),
-        Expr_::Var(v) => LLVMBuildLoad2(
-            builder,
-            LLVMInt8Type(),
-            *variables.get_key_value(&v).unwrap().1,
-            v.as_ptr() as *const _,
-        ),
+        Expr_::Int(v, suffix) => {
+            let ty = match (suffix, hint) {
+                (Some(suffix), _) => Type::Int(suffix),
+                (None, Some(Type::Int(ty))) => Type::Int(*ty),
+                (None, _) => Type::Int(IntType::U8),
+            };
+            (LLVMConstInt(llvm_type(&ty), v as u64, 0), ty)
+        }
+        Expr_::Bool(v) => (LLVMConstInt(LLVMInt1Type(), v.into(), 0), Type::Bool),
+        Expr_::Var(v) => {
+            let (ptr, ty) = variables.get(&v).cloned().unwrap();
+            let name = CString::new(v).unwrap();
+            (
+                LLVMBuildLoad2(builder, llvm_type(&ty), ptr, name.as_ptr()),
+                ty,
+            )
+        }
         Expr_::Add(a, b) => {
-            let a = match_expr(a.node, variables, builder);
-            let b = match_expr(b.node, variables, builder);
-            LLVMBuildAdd(builder, a, b, b"tmp\0".as_ptr() as *const _)
+            let (a, b, ty) = operands(*a, *b, variables, functions, builder, hint);
+            (
+                LLVMBuildAdd(builder, a, b, b"tmp\0".as_ptr() as *const _),
+                ty,
+            )
         }
         Expr_::Sub(a, b) => {
-            let a = match_expr(a.node, variables, builder);
-            let b = match_expr(b.node, variables, builder);
-            LLVMBuildSub(builder, a, b, b"tmp\0".as_ptr() as *const _)
+            let (a, b, ty) = operands(*a, *b, variables, functions, builder, hint);
+            (
+                LLVMBuildSub(builder, a, b, b"tmp\0".as_ptr() as *const _),
+                ty,
+            )
         }
         Expr_::Mul(a, b) => {
-            let a = match_expr(a.node, variables, builder);
-            let b = match_expr(b.node, variables, builder);
-            LLVMBuildMul(builder, a, b, b"tmp\0".as_ptr() as *const _)
+            let (a, b, ty) = operands(*a, *b, variables, functions, builder, hint);
+            (
+                LLVMBuildMul(builder, a, b, b"tmp\0".as_ptr() as *const _),
+                ty,
+            )
         }
         Expr_::Div(a, b) => {
-            let a = match_expr(a.node, variables, builder);
-            let b = match_expr(b.node, variables, builder);
-            LLVMBuildUDiv(builder, a, b, b"tmp\0".as_ptr() as *const _)
+            let (a, b, ty) = operands(*a, *b, variables, functions, builder, hint);
+            let value = if ty.is_signed() {
+                LLVMBuildSDiv(builder, a, b, b"tmp\0".as_ptr() as *const _)
+            } else {
+                LLVMBuildUDiv(builder, a, b, b"tmp\0".as_ptr() as *const _)
+            };
+            (value, ty)
         }
         Expr_::Mod(a, b) => {
-            let a = match_expr(a.node, variables, builder);
-            let b = match_expr(b.node, variables, builder);
-            LLVMBuildURem(builder, a, b, b"tmp\0".as_ptr() as *const _)
+            let (a, b, ty) = operands(*a, *b, variables, functions, builder, hint);
+            let value = if ty.is_signed() {
+                LLVMBuildSRem(builder, a, b, b"tmp\0".as_ptr() as *const _)
+            } else {
+                LLVMBuildURem(builder, a, b, b"tmp\0".as_ptr() as *const _)
+            };
+            (value, ty)
         }
         Expr_::Eq(a, b) => {
-            let a = match_expr(a.node, variables, builder);
-            let b = match_expr(b.node, variables, builder);
-            LLVMBuildIntCast(
+            let (a, b, ty) = operands(*a, *b, variables, functions, builder, None);
+            compare(
                 builder,
-                LLVMBuildICmp(
-                    builder,
-                    LLVMIntPredicate::LLVMIntEQ,
-                    a,
-                    b,
-                    b"tmp\0".as_ptr() as *const _,
-                ),
-                LLVMInt8Type(),
-                b"tmp\0".as_ptr() as *const _,
+                a,
+                b,
+                &ty,
+                LLVMIntPredicate::LLVMIntEQ,
+                LLVMIntPredicate::LLVMIntEQ,
             )
         }
         Expr_::NEq(a, b) => {
-            let a = match_expr(a.node, variables, builder);
-            let b = match_expr(b.node, variables, builder);
-            LLVMBuildIntCast(
+            let (a, b, ty) = operands(*a, *b, variables, functions, builder, None);
+            compare(
                 builder,
-                LLVMBuildICmp(
-                    builder,
-                    LLVMIntPredicate::LLVMIntNE,
-                    a,
-                    b,
-                    b"tmp\0".as_ptr() as *const _,
-                ),
-                LLVMInt8Type(),
-                b"tmp\0".as_ptr() as *const _,
+                a,
+                b,
+                &ty,
+                LLVMIntPredicate::LLVMIntNE,
+                LLVMIntPredicate::LLVMIntNE,
             )
         }
         Expr_::Gt(a, b) => {
-            let a = match_expr(a.node, variables, builder);
-            let b = match_expr(b.node, variables, builder);
-            LLVMBuildIntCast(
+            let (a, b, ty) = operands(*a, *b, variables, functions, builder, None);
+            compare(
                 builder,
-                LLVMBuildICmp(
-                    builder,
-                    LLVMIntPredicate::LLVMIntUGT,
-                    a,
-                    b,
-                    b"tmp\0".as_ptr() as *const _,
-                ),
-                LLVMInt8Type(),
-                b"tmp\0".as_ptr() as *const _,
+                a,
+                b,
+                &ty,
+                LLVMIntPredicate::LLVMIntSGT,
+                LLVMIntPredicate::LLVMIntUGT,
             )
         }
         Expr_::Lt(a, b) => {
-            let a = match_expr(a.node, variables, builder);
-            let b = match_expr(b.node, variables, builder);
-            LLVMBuildIntCast(
+            let (a, b, ty) = operands(*a, *b, variables, functions, builder, None);
+            compare(
                 builder,
-                LLVMBuildICmp(
-                    builder,
-                    LLVMIntPredicate::LLVMIntULT,
-                    a,
-                    b,
-                    b"tmp\0".as_ptr() as *const _,
-                ),
-                LLVMInt8Type(),
-                b"tmp\0".as_ptr() as *const _,
+                a,
+                b,
+                &ty,
+                LLVMIntPredicate::LLVMIntSLT,
+                LLVMIntPredicate::LLVMIntULT,
             )
         }
         Expr_::EGt(a, b) => {
-            let a = match_expr(a.node, variables, builder);
-            let b = match_expr(b.node, variables, builder);
-            LLVMBuildIntCast(
+            let (a, b, ty) = operands(*a, *b, variables, functions, builder, None);
+            compare(
                 builder,
-                LLVMBuildICmp(
-                    builder,
-                    LLVMIntPredicate::LLVMIntUGE,
-                    a,
-                    b,
-                    b"tmp\0".as_ptr() as *const _,
-                ),
-                LLVMInt8Type(),
-                b"tmp\0".as_ptr() as *const _,
+                a,
+                b,
+                &ty,
+                LLVMIntPredicate::LLVMIntSGE,
+                LLVMIntPredicate::LLVMIntUGE,
             )
         }
         Expr_::ELt(a, b) => {
-            let a = match_expr(a.node, variables, builder);
-            let b = match_expr(b.node, variables, builder);
-            LLVMBuildIntCast(
+            let (a, b, ty) = operands(*a, *b, variables, functions, builder, None);
+            compare(
                 builder,
-                LLVMBuildICmp(
-                    builder,
-                    LLVMIntPredicate::LLVMIntULE,
-                    a,
-                    b,
-                    b"tmp\0".as_ptr() as *const _,
-                ),
-                LLVMInt8Type(),
-                b"tmp\0".as_ptr() as *const _,
+                a,
+                b,
+                &ty,
+                LLVMIntPredicate::LLVMIntSLE,
+                LLVMIntPredicate::LLVMIntULE,
             )
         }
         Expr_::LNot(a) => {
-            let a = match_expr(a.node, variables, builder);
-            LLVMBuildIntCast(
-                builder,
+            let (a, ty) = match_expr(a.node, variables, functions, builder, None);
+            let a = truthy(builder, a, &ty);
+            (
                 LLVMBuildNot(builder, a, b"tmp\0".as_ptr() as *const _),
-                LLVMInt8Type(),
-                b"tmp\0".as_ptr() as *const _,
+                Type::Bool,
             )
         }
         Expr_::LAnd(a, b) => {
-            let a = match_expr(a.node, variables, builder);
-            let b = match_expr(b.node, variables, builder);
-            LLVMBuildIntCast(
-                builder,
+            let (a, a_ty) = match_expr(a.node, variables, functions, builder, None);
+            let a = truthy(builder, a, &a_ty);
+            let (b, b_ty) = match_expr(b.node, variables, functions, builder, None);
+            let b = truthy(builder, b, &b_ty);
+            (
                 LLVMBuildAnd(builder, a, b, b"tmp\0".as_ptr() as *const _),
-                LLVMInt8Type(),
-                b"tmp\0".as_ptr() as *const _,
+                Type::Bool,
             )
         }
         Expr_::LOr(a, b) => {
-            let a = match_expr(a.node, variables, builder);
-            let b = match_expr(b.node, variables, builder);
-            LLVMBuildIntCast(
-                builder,
+            let (a, a_ty) = match_expr(a.node, variables, functions, builder, None);
+            let a = truthy(builder, a, &a_ty);
+            let (b, b_ty) = match_expr(b.node, variables, functions, builder, None);
+            let b = truthy(builder, b, &b_ty);
+            (
                 LLVMBuildOr(builder, a, b, b"tmp\0".as_ptr() as *const _),
-                LLVMInt8Type(),
-                b"tmp\0".as_ptr() as *const _,
+                Type::Bool,
             )
         }
         Expr_::Assign(name, expr) => {
-            let name = name.clone();
-            let alloced = LLVMBuildAlloca(builder, LLVMInt8Type(), name.as_ptr() as *mut _);
-            variables.insert(name.clone(), alloced);
-            LLVMBuildStore(
-                builder,
-                match_expr(expr.node.clone(), &mut variables, builder),
-                alloced,
-            )
+            let (value, ty) = match_expr(expr.node, variables, functions, builder, None);
+            let name_c = CString::new(name.clone()).unwrap();
+            let alloced = entry_alloca(builder, llvm_type(&ty), name_c.as_ptr());
+            variables.insert(name, (alloced, ty));
+            (LLVMBuildStore(builder, value, alloced), Type::Void)
         }
         Expr_::ReAssign(name, expr) => {
-            let name = name.clone();
-            LLVMBuildStore(
-                builder,
-                match_expr(expr.node.clone(), &mut variables, builder),
-                *variables.get_key_value(&name).unwrap().1,
+            let (ptr, ty) = variables.get(&name).cloned().unwrap();
+            let (value, _) = match_expr(expr.node, variables, functions, builder, Some(&ty));
+            (LLVMBuildStore(builder, value, ptr), Type::Void)
+        }
+        Expr_::FunctionCall(name, args) => {
+            let (func, params, return_type) = functions.get(&name).cloned().unwrap();
+            let mut args = args
+                .into_iter()
+                .zip(&params)
+                .map(|(arg, ty)| match_expr(arg.node, variables, functions, builder, Some(ty)).0)
+                .collect::<Vec<LLVMValueRef>>();
+            // void calls cannot be named
+            let name = if return_type == Type::Void {
+                CString::default()
+            } else {
+                CString::new(name).unwrap()
+            };
+            (
+                LLVMBuildCall2(
+                    builder,
+                    LLVMGlobalGetValueType(func),
+                    func,
+                    args.as_mut_ptr(),
+                    args.len() as u32,
+                    name.as_ptr(),
+                ),
+                return_type,
             )
         }
-        _ => todo!(),
+        _ => unreachable!("statements are rejected by typeck where a value is expected"),
     }
 }
 
-pub unsafe fn iter_statements(
+/// Allocates a stack slot at the start of the function being built, so that a
+/// `var` in a loop reuses one slot instead of growing the stack each iteration.
+unsafe fn entry_alloca(
+    builder: LLVMBuilderRef,
+    ty: LLVMTypeRef,
+    name: *const c_char,
+) -> LLVMValueRef {
+    let entry = LLVMGetEntryBasicBlock(LLVMGetBasicBlockParent(LLVMGetInsertBlock(builder)));
+    let entry_builder = LLVMCreateBuilder();
+    let first = LLVMGetFirstInstruction(entry);
+    if first.is_null() {
+        LLVMPositionBuilderAtEnd(entry_builder, entry);
+    } else {
+        LLVMPositionBuilderBefore(entry_builder, first);
+    }
+    let slot = LLVMBuildAlloca(entry_builder, ty, name);
+    LLVMDisposeBuilder(entry_builder);
+    slot
+}
+
+/// The blocks a `continue` (`next`) and a `break` (`end`) of a loop jump to.
+struct LoopBlocks {
+    label: Option<String>,
+    next: LLVMBasicBlockRef,
+    end: LLVMBasicBlockRef,
+}
+
+/// The loop a `break` or `continue` with `label` jumps out of: the innermost
+/// one, or the innermost with that label.
+fn target_loop<'a>(loops: &'a [LoopBlocks], label: &Option<String>) -> &'a LoopBlocks {
+    loops
+        .iter()
+        .rev()
+        .find(|blocks| label.is_none() || blocks.label == *label)
+        .expect("`break` and `continue` are only type checked inside their loop")
+}
+
+/// Whether the block being built already ends in a terminator like `ret`.
+unsafe fn terminated(builder: *mut LLVMBuilder) -> bool {
+    !LLVMGetBasicBlockTerminator(LLVMGetInsertBlock(builder)).is_null()
+}
+
+unsafe fn iter_statements(
     builder: *mut LLVMBuilder,
     expr: Vec<Expr>,
-    mut variables: &mut HashMap<String, LLVMValueRef>,
+    variables: &mut Variables,
+    functions: &Functions,
+    return_type: &Type,
+    loops: &mut Vec<LoopBlocks>,
+    debug: Option<&DebugInfo>,
 ) {
+    // variables declared in the block go out of scope at its end, uncovering any they shadowed
+    let outer = variables.clone();
     for y in expr {
+        // anything after a `return` is unreachable
+        if terminated(builder) {
+            break;
+        }
+        if let Some(debug) = debug {
+            debug.set_location(builder, y.span);
+        }
         match y.node {
-            Expr_::Assign(name, expr) => {
-                let name = name.clone();
-                let alloced = LLVMBuildAlloca(builder, LLVMInt8Type(), name.as_ptr() as *mut _);
-                LLVMBuildStore(
-                    builder,
-                    match_expr(expr.node.clone(), &mut variables, builder),
-                    alloced,
-                );
-                variables.insert(name.clone(), alloced);
+            Expr_::Assign(name, value) => {
+                let node = Expr_::Assign(name.clone(), value);
+                match_expr(node, variables, functions, builder, None);
+                if let Some(debug) = debug {
+                    let (slot, ty) = &variables[&name];
+                    debug.declare_variable(builder, *slot, &name, ty, y.span, None);
+                }
             }
-            Expr_::ReAssign(name, expr) => {
-                let name = name.clone();
-                LLVMBuildStore(
-                    builder,
-                    match_expr(expr.node.clone(), &mut variables, builder),
-                    *variables.get_key_value(&name).unwrap().1,
-                );
+            Expr_::ReAssign(..) => {
+                match_expr(y.node, variables, functions, builder, None);
             }
             Expr_::IfElse(expr, if_b, else_b) => {
-                let condition = match_expr(expr.node, variables, builder);
-                let condition = LLVMBuildICmp(
-                    builder,
-                    LLVMIntPredicate::LLVMIntNE,
-                    condition,
-                    LLVMConstInt(LLVMInt8Type(), 0, 0),
-                    b"tmp\0".as_ptr() as *const _,
-                );
+                let (condition, ty) = match_expr(expr.node, variables, functions, builder, None);
+                let condition = truthy(builder, condition, &ty);
                 let func = LLVMGetBasicBlockParent(LLVMGetInsertBlock(builder));
                 let then_block = LLVMAppendBasicBlock(func, b"then\0".as_ptr() as *const _);
-                let else_block = LLVMAppendBasicBlock(func, b"else\0".as_ptr() as *const _);
+                let else_block = (!else_b.is_empty())
+                    .then(|| LLVMAppendBasicBlock(func, b"else\0".as_ptr() as *const _));
                 let end = LLVMAppendBasicBlock(func, b"end\0".as_ptr() as *const _);
-                LLVMBuildCondBr(builder, condition, then_block, else_block);
+                LLVMBuildCondBr(builder, condition, then_block, else_block.unwrap_or(end));
 
                 LLVMPositionBuilderAtEnd(builder, then_block);
-                iter_statements(builder, if_b, variables);
-                LLVMBuildBr(builder, end);
+                iter_statements(
+                    builder,
+                    if_b,
+                    variables,
+                    functions,
+                    return_type,
+                    loops,
+                    debug,
+                );
+                if !terminated(builder) {
+                    LLVMBuildBr(builder, end);
+                }
 
-                LLVMPositionBuilderAtEnd(builder, else_block);
-                iter_statements(builder, else_b, variables);
-                LLVMBuildBr(builder, end);
+                if let Some(else_block) = else_block {
+                    LLVMPositionBuilderAtEnd(builder, else_block);
+                    iter_statements(
+                        builder,
+                        else_b,
+                        variables,
+                        functions,
+                        return_type,
+                        loops,
+                        debug,
+                    );
+                    if !terminated(builder) {
+                        LLVMBuildBr(builder, end);
+                    }
+                }
 
                 LLVMPositionBuilderAtEnd(builder, end);
             }
-            Expr_::ForLoop(init, comp, run, block) => {
-                match_expr(init.node, variables, builder);
+            Expr_::ForLoop(label, init, comp, run, block) => {
+                let outer = variables.clone();
+                let counter = match &init.node {
+                    Expr_::Assign(name, _) => Some(name.clone()),
+                    _ => None,
+                };
+                match_expr(init.node, variables, functions, builder, None);
+                if let (Some(debug), Some(name)) = (debug, counter) {
+                    let (slot, ty) = &variables[&name];
+                    debug.declare_variable(builder, *slot, &name, ty, init.span, None);
+                }
 
-                let condition = match_expr(comp.node.clone(), variables, builder);
-                let condition = LLVMBuildICmp(
-                    builder,
-                    LLVMIntPredicate::LLVMIntNE,
-                    condition,
-                    LLVMConstInt(LLVMInt8Type(), 0, 0),
-                    b"tmp\0".as_ptr() as *const _,
-                );
+                let (condition, ty) =
+                    match_expr(comp.node.clone(), variables, functions, builder, None);
+                let condition = truthy(builder, condition, &ty);
 
                 let func = LLVMGetBasicBlockParent(LLVMGetInsertBlock(builder));
                 let loop_block = LLVMAppendBasicBlock(func, b"loop\0".as_ptr() as *const _);
+                let next = LLVMAppendBasicBlock(func, b"next\0".as_ptr() as *const _);
                 let end = LLVMAppendBasicBlock(func, b"end\0".as_ptr() as *const _);
                 LLVMBuildCondBr(builder, condition, loop_block, end);
 
                 LLVMPositionBuilderAtEnd(builder, loop_block);
-                iter_statements(builder, block, variables);
-                match_expr(run.node, variables, builder);
-                let condition = match_expr(comp.node, variables, builder);
-                let condition = LLVMBuildICmp(
+                loops.push(LoopBlocks { label, next, end });
+                iter_statements(
                     builder,
-                    LLVMIntPredicate::LLVMIntNE,
-                    condition,
-                    LLVMConstInt(LLVMInt8Type(), 0, 0),
-                    b"tmp\0".as_ptr() as *const _,
+                    block,
+                    variables,
+                    functions,
+                    return_type,
+                    loops,
+                    debug,
                 );
+                loops.pop();
+                if !terminated(builder) {
+                    LLVMBuildBr(builder, next);
+                }
+
+                LLVMPositionBuilderAtEnd(builder, next);
+                if let Some(debug) = debug {
+                    debug.set_location(builder, y.span);
+                }
+                match_expr(run.node, variables, functions, builder, None);
+                let (condition, ty) = match_expr(comp.node, variables, functions, builder, None);
+                let condition = truthy(builder, condition, &ty);
                 LLVMBuildCondBr(builder, condition, loop_block, end);
 
                 LLVMPositionBuilderAtEnd(builder, end);
+                *variables = outer;
             }
-            Expr_::Byte(v) => {
-                LLVMBuildRet(builder, LLVMConstInt(LLVMInt8Type(), v.into(), 0));
+            Expr_::While(label, cond, block) => {
+                let func = LLVMGetBasicBlockParent(LLVMGetInsertBlock(builder));
+                let next = LLVMAppendBasicBlock(func, b"while\0".as_ptr() as *const _);
+                let body = LLVMAppendBasicBlock(func, b"body\0".as_ptr() as *const _);
+                let end = LLVMAppendBasicBlock(func, b"end\0".as_ptr() as *const _);
+                LLVMBuildBr(builder, next);
+
+                LLVMPositionBuilderAtEnd(builder, next);
+                let (condition, ty) = match_expr(cond.node, variables, functions, builder, None);
+                let condition = truthy(builder, condition, &ty);
+                LLVMBuildCondBr(builder, condition, body, end);
+
+                LLVMPositionBuilderAtEnd(builder, body);
+                loops.push(LoopBlocks { label, next, end });
+                iter_statements(
+                    builder,
+                    block,
+                    variables,
+                    functions,
+                    return_type,
+                    loops,
+                    debug,
+                );
+                loops.pop();
+                if !terminated(builder) {
+                    LLVMBuildBr(builder, next);
+                }
+
+                LLVMPositionBuilderAtEnd(builder, end);
             }
-            Expr_::Var(v) => {
-                LLVMBuildRet(
+            Expr_::Loop(label, block) => {
+                let func = LLVMGetBasicBlockParent(LLVMGetInsertBlock(builder));
+                let next = LLVMAppendBasicBlock(func, b"loop\0".as_ptr() as *const _);
+                let end = LLVMAppendBasicBlock(func, b"end\0".as_ptr() as *const _);
+                LLVMBuildBr(builder, next);
+
+                LLVMPositionBuilderAtEnd(builder, next);
+                loops.push(LoopBlocks { label, next, end });
+                iter_statements(
                     builder,
-                    LLVMBuildLoad2(
-                        builder,
-                        LLVMInt8Type(),
-                        *variables.get_key_value(&v).unwrap().1,
-                        v.as_ptr() as *const _,
-                    ),
+                    block,
+                    variables,
+                    functions,
+                    return_type,
+                    loops,
+                    debug,
+                );
+                loops.pop();
+                if !terminated(builder) {
+                    LLVMBuildBr(builder, next);
+                }
+
+                LLVMPositionBuilderAtEnd(builder, end);
+            }
+            Expr_::CallStatement(name, args) => {
+                match_expr(
+                    Expr_::FunctionCall(name, args),
+                    variables,
+                    functions,
+                    builder,
+                    None,
                 );
             }
+            Expr_::Break(label) => {
+                LLVMBuildBr(builder, target_loop(loops, &label).end);
+            }
+            Expr_::Continue(label) => {
+                LLVMBuildBr(builder, target_loop(loops, &label).next);
+            }
             Expr_::Pass => {}
-            _ => todo!(),
+            Expr_::Declare(..) | Expr_::Define(..) => {
+                unreachable!("nested functions are rejected by typeck")
+            }
+            // any other expression is a `return`
+            node => {
+                let (value, _) = match_expr(node, variables, functions, builder, Some(return_type));
+                // only a call returning `!` is type checked as the value of a `!` function
+                if *return_type == Type::Void {
+                    LLVMBuildRetVoid(builder);
+                } else {
+                    LLVMBuildRet(builder, value);
+                }
+            }
         }
     }
+    *variables = outer;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::PoisonError;
+
+    use super::GLOBAL_CONTEXT;
+    use crate::session::{Options, Session};
+
+    /// JIT-compiles `src` and returns what its `main` returns.
+    fn run(src: &str) -> i64 {
+        let _llvm = GLOBAL_CONTEXT
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let session = Session::new(src, Options::default());
+        session
+            .parse()
+            .and_then(|program| session.check(program))
+            .and_then(|program| session.run(program))
+            .unwrap_or_else(|err| panic!("{}", session.report(&err)))
+    }
+
+    #[test]
+    fn blocks_end_the_scope_of_their_variables() {
+        assert_eq!(
+            run("declare main = i32;
+                 define main = { var y = 1i32; while false { var y = 2i32; } return y; };"),
+            1
+        );
+        assert_eq!(
+            run("declare main = i32;
+                 define main = {
+                     var y = 1i32;
+                     if true { var y = 2i32; mutate y = 3i32; } else { var y = 4i32; }
+                     for var y = 0i32; y < 10i32; mutate y = y + 1i32; { var z = y; }
+                     return y;
+                 };"),
+            1
+        );
+    }
+
+    #[test]
+    fn variables_in_loops_do_not_grow_the_stack() {
+        assert_eq!(
+            run("declare main = i32;
+                 define main = {
+                     var n = 0i32;
+                     while n < 10000000i32 { var next = n + 1i32; mutate n = next; }
+                     return n;
+                 };"),
+            10000000
+        );
+    }
+
+    #[test]
+    fn bools_are_returned_as_their_low_bit() {
+        assert_eq!(
+            run("declare main = bool; define main = { return 1i32 < 2i32; };"),
+            1
+        );
+        assert_eq!(
+            run("declare main = bool; define main = { return 2i32 < 1i32; };"),
+            0
+        );
+    }
 }
@@ -1,117 +1,434 @@
 use clap::Parser as ClapParser;
-use std::io::prelude::*;
-use std::time::Instant;
-use std::{fs::File, process::Command};
-use tracing::Level;
-use tracing_subscriber::FmtSubscriber;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{fs, process};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
 
-use crate::llvm::compile_llvm;
-
-mod lexer;
-mod llvm;
-mod parser;
+use sugar_lang::llvm::{self, OptLevel};
+use sugar_lang::repl;
+use sugar_lang::session::{CheckedProgram, CompileError, EmitKind, Options, Session};
 
 #[derive(ClapParser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Subcommand>,
     #[clap(short, long, default_value = "main.sug")]
     file: String,
+    /// Comma-separated artifacts to write: tokens, ast, llvm-ir, llvm-bc, asm, obj or link
+    #[clap(long, value_delimiter = ',', default_value = "link")]
+    emit: Vec<EmitKind>,
+    /// Output path, used as the file stem when several artifacts are emitted;
+    /// `-` writes a textual artifact to stdout
     #[clap(short, long)]
-    printing: bool,
+    output: Option<PathBuf>,
+    /// Directory the artifacts are written to, unless `-o` names them
+    #[clap(long, default_value = "build")]
+    out_dir: PathBuf,
     #[clap(short, long)]
     strip: bool,
-    #[clap(short, long)]
+    /// Optimize, at `-O3` unless `-O` says otherwise
+    #[clap(short, long, global = true)]
     release: bool,
+    /// Emit DWARF debug info
+    #[clap(short = 'g', global = true)]
+    debug_info: bool,
+    /// Target triple to compile for, e.g. `aarch64-linux-gnu` or `wasm32`
+    #[clap(long, value_name = "TRIPLE")]
+    target: Option<String>,
+    /// Print information about the compiler instead of compiling
+    #[clap(long, arg_enum, value_name = "WHAT")]
+    print: Option<PrintKind>,
+    /// Optimization level: 0, 1, 2, 3, s or z
+    #[clap(short = 'O', global = true, value_name = "LEVEL")]
+    opt_level: Option<OptLevel>,
     #[clap(short = 'S', long)]
     r#static: bool,
+    /// Linker to use, instead of the first of `cc`, `clang` or `ld.lld` found
+    #[clap(long, value_name = "PATH")]
+    linker: Option<PathBuf>,
+    /// Link against a library
+    #[clap(short = 'l', value_name = "LIB")]
+    libraries: Vec<String>,
+    /// Add a directory to the library search path
+    #[clap(short = 'L', value_name = "DIR")]
+    library_paths: Vec<PathBuf>,
+    /// Pass an argument to the linker as it is
+    #[clap(long, value_name = "ARG", allow_hyphen_values = true)]
+    link_arg: Vec<String>,
+    /// Log more about each step, up to `-vvv`; `SUGAR_LOG` overrides this
+    #[clap(short, long, global = true, parse(from_occurrences))]
+    verbose: u8,
+    /// Only print errors
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Print how long each compiler pass took, as a table or as JSON
+    #[clap(
+        long,
+        global = true,
+        arg_enum,
+        value_name = "FORMAT",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "human"
+    )]
+    time_passes: Option<TimeFormat>,
 }
 
-fn main() {
-    let now = Instant::now();
-    // Subscriber Stuff
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::TRACE)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber).expect("Failed Setting Global Subscriber");
+#[derive(clap::ArgEnum, Debug, Clone, Copy)]
+enum PrintKind {
+    /// The targets LLVM can generate code for
+    Targets,
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy)]
+enum TimeFormat {
+    Human,
+    Json,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Subcommand {
+    /// JIT-compile a file and run it, exiting with the value `main` returns
+    Run {
+        /// The file to run, instead of `--file`
+        file: Option<String>,
+    },
+    /// Read declarations, definitions and expressions interactively
+    Repl,
+}
+
+impl Args {
+    /// The source file to compile or run.
+    fn input(&self) -> &str {
+        match &self.command {
+            Some(Subcommand::Run { file: Some(file) }) => file,
+            _ => &self.file,
+        }
+    }
+
+    /// Where the artifact of `kind` is written, creating its directory. A lone
+    /// artifact goes exactly to `-o`, otherwise each one gets the stem (`-o`, or
+    /// the input file's stem in `--out-dir`) with its own extension.
+    fn output_path(&self, kind: EmitKind) -> io::Result<PathBuf> {
+        let path = match &self.output {
+            Some(path) if self.emit.len() == 1 => path.clone(),
+            output => {
+                let stem = output.clone().unwrap_or_else(|| {
+                    let name = Path::new(self.input()).file_stem();
+                    self.out_dir.join(name.unwrap_or_else(|| "out".as_ref()))
+                });
+                match kind.extension() {
+                    Some(ext) => stem.with_extension(ext),
+                    None => stem,
+                }
+            }
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(path)
+    }
+
+    /// The log filter used unless `SUGAR_LOG` is set.
+    fn log_level(&self) -> &'static str {
+        match (self.quiet, self.verbose) {
+            (true, _) => "error",
+            (false, 0) => "warn",
+            (false, 1) => "info",
+            (false, 2) => "debug",
+            (false, _) => "trace",
+        }
+    }
+
+    fn emits(&self, kind: EmitKind) -> bool {
+        self.emit.contains(&kind)
+    }
+}
 
-    let command = if cfg!(target_os = "windows") {
-        Command::new("cmd").args(["/C", "mkdir build"]).output()
+/// Writes a textual artifact, to stdout if `path` is `-`.
+fn write_text(path: &Path, text: &str) -> io::Result<()> {
+    if path == Path::new("-") {
+        io::stdout().write_all(text.as_bytes())
     } else {
-        Command::new("sh").args(["-c", "mkdir build"]).output()
-    };
-    command.unwrap();
+        fs::write(path, text)
+    }
+}
 
-    let args = Args::parse();
-    let contents = {
-        let mut file = File::open(args.file.as_str()).unwrap();
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
-        contents
+/// Parses and type checks the session's source.
+fn check(session: &Session) -> Result<CheckedProgram, CompileError> {
+    session.check(session.parse()?)
+}
+
+/// Writes every artifact asked for with `--emit`.
+fn build(args: &Args, session: &Session) -> Result<(), CompileError> {
+    if args.emits(EmitKind::Tokens) {
+        let tokens: String = session
+            .tokens()
+            .iter()
+            .map(|tok| format!("{:?}\n", tok))
+            .collect();
+        write_text(&args.output_path(EmitKind::Tokens)?, &tokens)?;
+    }
+    if !args.emit.iter().any(|kind| kind != &EmitKind::Tokens) {
+        return Ok(());
+    }
+
+    // the AST is written before the type check, so it can help find a type error
+    let program = session.parse()?;
+    if args.emits(EmitKind::Ast) {
+        let ast = format!("{:#?}\n", program.stmts);
+        write_text(&args.output_path(EmitKind::Ast)?, &ast)?;
+    }
+    let program = session.check(program)?;
+    if !args.emit.iter().any(|kind| kind.needs_codegen()) {
+        return Ok(());
+    }
+
+    let module = session.codegen(program)?;
+    if args.emits(EmitKind::LlvmIr) {
+        write_text(
+            &args.output_path(EmitKind::LlvmIr)?,
+            &module.print_to_string(),
+        )?;
+    }
+    if args.emits(EmitKind::LlvmBc) {
+        session.emit_bitcode(&module, &args.output_path(EmitKind::LlvmBc)?)?;
+    }
+    if args.emits(EmitKind::Asm) {
+        session.emit_assembly(&module, &args.output_path(EmitKind::Asm)?)?;
+    }
+
+    // linking needs an object file, which is only kept if it was asked for
+    let object = if args.emits(EmitKind::Obj) {
+        args.output_path(EmitKind::Obj)?
+    } else {
+        args.output_path(EmitKind::Link)?.with_extension("o")
     };
-    let lexer = lexer::Lexer::new(&contents).inspect(|tok| {
-        if args.printing {
-            println!("tok: {:?}", tok)
+    if args.emits(EmitKind::Obj) || args.emits(EmitKind::Link) {
+        session.emit_object(&module, &object)?;
+    }
+    if args.emits(EmitKind::Link) {
+        let linked = session.link(&object, &args.output_path(EmitKind::Link)?);
+        if !args.emits(EmitKind::Obj) {
+            fs::remove_file(&object)?;
         }
-    });
-    let program = parser::parse(lexer).unwrap();
+        linked?;
+    }
+    Ok(())
+}
 
-    if args.printing {
-        println!("{:#?}", program.stmts);
+/// Prints the time each pass of `session` took to stderr.
+fn print_pass_times(session: &Session, format: TimeFormat) {
+    let passes = session.pass_times();
+    let total: Duration = passes.iter().map(|(_, time)| *time).sum();
+    let ms = |time: Duration| time.as_secs_f64() * 1000.0;
+    match format {
+        TimeFormat::Human => {
+            for (pass, time) in &passes {
+                eprintln!("time: {:>10.3}ms  {}", ms(*time), pass);
+            }
+            eprintln!("time: {:>10.3}ms  total", ms(total));
+        }
+        TimeFormat::Json => {
+            let passes = passes
+                .iter()
+                .map(|(pass, time)| format!("{{\"pass\":\"{}\",\"ms\":{:.3}}}", pass, ms(*time)))
+                .collect::<Vec<_>>()
+                .join(",");
+            eprintln!("{{\"passes\":[{}],\"total_ms\":{:.3}}}", passes, ms(total));
+        }
     }
+}
 
-    let _ = unsafe { compile_llvm(program.stmts) };
+/// Prints `err` and exits with its exit code.
+fn fail(session: &Session, err: &CompileError) -> ! {
+    eprint!("{}", session.report(err));
+    process::exit(err.exit_code());
+}
 
-    let prefix;
-    let cmd;
+fn main() {
+    let now = Instant::now();
+    let args = Args::parse();
 
-    if cfg!(target_os = "windows") {
-        prefix = "/C";
-        cmd = "cmd";
-    } else {
-        prefix = "-c";
-        cmd = "sh";
-    }
-    Command::new(cmd)
-        .args([prefix, "llvm-dis out.bc"])
-        .output()
-        .unwrap();
-
-    Command::new(cmd)
-        .args([prefix, "llc -filetype=obj out.ll -o out.o"])
-        .output()
-        .unwrap();
-    if args.r#static {
-        if args.release {
-            Command::new(cmd)
-                .args([prefix, "ld.lld -O3 -static out.o -o out;"])
-                .output()
-                .unwrap();
-        } else {
-            Command::new(cmd)
-                .args([prefix, "ld.lld -static out.o -o out;"])
-                .output()
-                .unwrap();
+    // Subscriber Stuff
+    let filter =
+        EnvFilter::try_from_env("SUGAR_LOG").unwrap_or_else(|_| EnvFilter::new(args.log_level()));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_span_events(FmtSpan::CLOSE)
+        .init();
+    if let Some(Subcommand::Repl) = args.command {
+        if let Err(err) = repl::run() {
+            eprintln!("error: {}", err);
+            process::exit(err.exit_code());
         }
-    } else {
-        if args.release {
-            Command::new(cmd)
-                .args([prefix, "ld.lld -O3 out.o -o out;"])
-                .output()
-                .unwrap();
+        return;
+    }
+
+    if let Some(PrintKind::Targets) = args.print {
+        println!("host: {}", llvm::host_triple());
+        println!("targets:");
+        for (name, description) in llvm::targets() {
+            println!("    {:<12} - {}", name, description);
+        }
+        return;
+    }
+
+    let options = Options {
+        opt_level: args.opt_level.unwrap_or(if args.release {
+            OptLevel::O3
         } else {
-            Command::new(cmd)
-                .args([prefix, "ld.lld out.o -o out;"])
-                .output()
-                .unwrap();
+            OptLevel::O0
+        }),
+        debug_info: args.debug_info,
+        target: args.target.clone(),
+        r#static: args.r#static,
+        strip: args.strip,
+        linker: args.linker.clone(),
+        libraries: args.libraries.clone(),
+        library_paths: args.library_paths.clone(),
+        link_args: args.link_arg.clone(),
+    };
+    let session = match Session::from_path(args.input(), options) {
+        Ok(session) => session,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            process::exit(err.exit_code());
+        }
+    };
+
+    if let Some(Subcommand::Run { .. }) = args.command {
+        let result = check(&session).and_then(|program| session.run(program));
+        if let Some(format) = args.time_passes {
+            print_pass_times(&session, format);
+        }
+        match result {
+            Ok(code) => process::exit(code as i32),
+            Err(err) => fail(&session, &err),
         }
     }
-    if args.strip {
-        Command::new(cmd)
-            .args([prefix, "strip out;"])
-            .output()
-            .unwrap();
+    let result = build(&args, &session);
+    if let Some(format) = args.time_passes {
+        print_pass_times(&session, format);
+    }
+    if let Err(err) = result {
+        fail(&session, &err);
     }
 
-    let elapsed = now.elapsed();
-    println!("Elapsed: {:.3?}", elapsed);
+    if !args.quiet {
+        let elapsed = now.elapsed();
+        eprintln!("Elapsed: {:.3?}", elapsed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    /// Parses `args` as if they followed the binary name.
+    fn args(args: &[&str]) -> Args {
+        Args::parse_from(["sugar-lang"].iter().chain(args))
+    }
+
+    /// A directory for the test `name` to write into.
+    fn out_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("sugar-test-{}-{}", process::id(), name))
+    }
+
+    #[test]
+    fn emit_takes_a_comma_separated_list() {
+        assert_eq!(args(&[]).emit, [EmitKind::Link]);
+        assert_eq!(
+            args(&["--emit", "tokens,llvm-ir", "--emit=obj"]).emit,
+            [EmitKind::Tokens, EmitKind::LlvmIr, EmitKind::Obj]
+        );
+        assert!(Args::try_parse_from(["sugar-lang", "--emit", "exe"]).is_err());
+    }
+
+    #[test]
+    fn outputs_are_named_after_the_input() {
+        let dir = out_dir("input");
+        let dir_arg = dir.to_str().unwrap();
+        let args = args(&[
+            "-f",
+            "src/toy.sug",
+            "--out-dir",
+            dir_arg,
+            "--emit",
+            "obj,link",
+        ]);
+        assert_eq!(args.output_path(EmitKind::Link).unwrap(), dir.join("toy"));
+        assert_eq!(args.output_path(EmitKind::Obj).unwrap(), dir.join("toy.o"));
+        assert!(dir.is_dir());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn run_takes_the_input_from_its_argument() {
+        let dir = out_dir("run");
+        let args = args(&["--out-dir", dir.to_str().unwrap(), "run", "other.sug"]);
+        assert_eq!(args.input(), "other.sug");
+        assert_eq!(args.output_path(EmitKind::Link).unwrap(), dir.join("other"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_lone_artifact_goes_exactly_to_output() {
+        let dir = out_dir("lone");
+        let path = dir.join("sub").join("prog.ll");
+        let path_arg = path.to_str().unwrap();
+        let args = args(&["--emit", "llvm-ir", "-o", path_arg]);
+        assert_eq!(args.output_path(EmitKind::LlvmIr).unwrap(), path);
+        assert!(path.parent().unwrap().is_dir());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn several_artifacts_share_the_output_stem() {
+        let dir = out_dir("stem");
+        let stem = dir.join("prog");
+        let args = args(&["--emit", "asm,llvm-bc,link", "-o", stem.to_str().unwrap()]);
+        assert_eq!(args.output_path(EmitKind::Asm).unwrap(), dir.join("prog.s"));
+        assert_eq!(
+            args.output_path(EmitKind::LlvmBc).unwrap(),
+            dir.join("prog.bc")
+        );
+        assert_eq!(args.output_path(EmitKind::Link).unwrap(), stem);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_ast_is_written_before_the_type_check() {
+        let dir = out_dir("ast");
+        let path = dir.join("prog.ast");
+        let args = args(&["--emit", "ast", "-o", path.to_str().unwrap()]);
+        let session = Session::new(
+            "declare main = i32; define main = { return true; };",
+            Options::default(),
+        );
+        assert!(matches!(build(&args, &session), Err(CompileError::Type(_))));
+        assert!(fs::read_to_string(&path).unwrap().contains("Define("));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_source_is_lexed_once() {
+        let dir = out_dir("lex");
+        let stem = dir.join("prog");
+        let args = args(&["--emit", "tokens,ast", "-o", stem.to_str().unwrap()]);
+        let session = Session::new(
+            "declare main = i32; define main = 0i32;",
+            Options::default(),
+        );
+        build(&args, &session).unwrap();
+        let passes: Vec<_> = session.pass_times().iter().map(|(pass, _)| *pass).collect();
+        assert_eq!(passes, ["lex", "parse", "typeck"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
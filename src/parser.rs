@@ -1,7 +1,8 @@
 #![allow(unused_braces)]
 
-use crate::lexer::{Span, Token};
+use crate::lexer::{LexError, Span, Token};
 use crate::parser::Token::*;
+use crate::types::{IntType, Type};
 
 #[derive(Debug)]
 pub struct Program {
@@ -34,21 +35,86 @@ pub enum Expr_ {
 
     Var(String),
 
-    Declare(String, Vec<String>),
+    /// A function signature, with the ABI of an `extern` declaration, whose
+    /// function is defined outside of Sugar.
+    Declare(String, Vec<Type>, Option<String>),
     Define(String, Vec<String>, Vec<Expr>),
     FunctionCall(String, Vec<Expr>),
+    /// A call in statement position, whose value (if any) is dropped.
+    CallStatement(String, Vec<Expr>),
 
     Assign(String, Box<Expr>),
     ReAssign(String, Box<Expr>),
 
+    /// `if cond { .. } else { .. }`, where a missing `else` is an empty block and
+    /// `else if` is an `IfElse` on its own.
     IfElse(Box<Expr>, Vec<Expr>, Vec<Expr>),
-    ForLoop(Box<Expr>, Box<Expr>, Box<Expr>, Vec<Expr>),
+    /// A loop and its label (`'name:`) come first in each of the loops.
+    ForLoop(Option<String>, Box<Expr>, Box<Expr>, Box<Expr>, Vec<Expr>),
+    While(Option<String>, Box<Expr>, Vec<Expr>),
+    Loop(Option<String>, Vec<Expr>),
+    /// Leaves the innermost loop, or the one with the label.
+    Break(Option<String>),
+    /// Starts the next iteration of the innermost loop, or the one with the label.
+    Continue(Option<String>),
 
-    Byte(u8),
+    Int(u128, Option<IntType>),
+    Bool(bool),
 
     Pass,
 }
 
+impl Expr {
+    /// Calls `f` on this expression and then on every expression nested inside it.
+    pub fn walk(&self, f: &mut impl FnMut(&Expr)) {
+        f(self);
+        match &self.node {
+            Expr_::Add(a, b)
+            | Expr_::Sub(a, b)
+            | Expr_::Mul(a, b)
+            | Expr_::Div(a, b)
+            | Expr_::Mod(a, b)
+            | Expr_::Eq(a, b)
+            | Expr_::NEq(a, b)
+            | Expr_::Gt(a, b)
+            | Expr_::Lt(a, b)
+            | Expr_::EGt(a, b)
+            | Expr_::ELt(a, b)
+            | Expr_::LAnd(a, b)
+            | Expr_::LOr(a, b) => {
+                a.walk(f);
+                b.walk(f);
+            }
+            Expr_::LNot(a) | Expr_::Assign(_, a) | Expr_::ReAssign(_, a) => a.walk(f),
+            Expr_::Define(_, _, block)
+            | Expr_::FunctionCall(_, block)
+            | Expr_::CallStatement(_, block) => block.iter().for_each(|e| e.walk(f)),
+            Expr_::IfElse(cond, if_b, else_b) => {
+                cond.walk(f);
+                if_b.iter().chain(else_b).for_each(|e| e.walk(f));
+            }
+            Expr_::ForLoop(_, init, comp, run, block) => {
+                init.walk(f);
+                comp.walk(f);
+                run.walk(f);
+                block.iter().for_each(|e| e.walk(f));
+            }
+            Expr_::While(_, cond, block) => {
+                cond.walk(f);
+                block.iter().for_each(|e| e.walk(f));
+            }
+            Expr_::Loop(_, block) => block.iter().for_each(|e| e.walk(f)),
+            Expr_::Var(_)
+            | Expr_::Declare(..)
+            | Expr_::Int(_, _)
+            | Expr_::Bool(_)
+            | Expr_::Break(_)
+            | Expr_::Continue(_)
+            | Expr_::Pass => {}
+        }
+    }
+}
+
 plex::parser! {
     fn parse_(Token, Span);
 
@@ -75,7 +141,11 @@ plex::parser! {
     outer: Expr {
         Declare Ident(name) Equals declare_args[args] => Expr {
             span: span!(),
-            node: Expr_::Declare(name, args)
+            node: Expr_::Declare(name, args, None)
+        },
+        Declare Extern Str(abi) Ident(name) Equals declare_args[args] => Expr {
+            span: span!(),
+            node: Expr_::Declare(name, args, Some(abi))
         },
         Define Ident(name) define_args[args] Equals exprs[block] => Expr {
             span: span!(),
@@ -109,35 +179,55 @@ plex::parser! {
             e.append(&mut m);
             e
         },
-        Return Byte(e) SemiColon exprwrap[mut m] => {
+        Return term[r] SemiColon exprwrap[mut m] => {
+            let mut e = vec![r];
+            e.append(&mut m);
+            e
+        },
+        If term[c] LBrace expr[b] RBrace else_branch[e] exprwrap[mut m] => {
+            let mut e = vec![Expr {
+                span: span!(),
+                node: Expr_::IfElse(Box::new(c), b, e)
+            }];
+            e.append(&mut m);
+            e
+        },
+        label[l] For for_init[a] term[b] SemiColon expr[c] LBrace expr[e] RBrace exprwrap[mut m] => {
             let mut e = vec![Expr {
                 span: span!(),
-                node: Expr_::Byte(e)
+                node: Expr_::ForLoop(l, Box::new(a), Box::new(b.clone()), Box::new(c[0].clone()), e.clone())
             }];
             e.append(&mut m);
             e
         },
-        Return Ident(name) SemiColon exprwrap[mut m] => {
+        label[l] While term[c] LBrace expr[b] RBrace exprwrap[mut m] => {
             let mut e = vec![Expr {
                 span: span!(),
-                node: Expr_::Var(name)
+                node: Expr_::While(l, Box::new(c), b)
             }];
             e.append(&mut m);
             e
         },
-        Return expr[e] SemiColon => e,
-        If exprs[e] SemiColon LBrace expr[b] RBrace SemiColon Else SemiColon LBrace expr[c] RBrace SemiColon exprwrap[mut m] => {
+        label[l] Loop LBrace expr[b] RBrace exprwrap[mut m] => {
             let mut e = vec![Expr {
                 span: span!(),
-                node: Expr_::IfElse(Box::new(e[0].clone()), b.clone(), c.clone())
+                node: Expr_::Loop(l, b)
             }];
             e.append(&mut m);
             e
         },
-        For expr[a] term[b] SemiColon expr[c] LBrace expr[e] RBrace SemiColon exprwrap[mut m] => {
+        Break target[l] SemiColon exprwrap[mut m] => {
             let mut e = vec![Expr {
                 span: span!(),
-                node: Expr_::ForLoop(Box::new(a[0].clone()), Box::new(b.clone()), Box::new(c[0].clone()), e.clone())
+                node: Expr_::Break(l)
+            }];
+            e.append(&mut m);
+            e
+        },
+        Continue target[l] SemiColon exprwrap[mut m] => {
+            let mut e = vec![Expr {
+                span: span!(),
+                node: Expr_::Continue(l)
             }];
             e.append(&mut m);
             e
@@ -149,9 +239,56 @@ plex::parser! {
             }];
             e.append(&mut m);
             e
+        },
+        Ident(name) LParen RParen SemiColon exprwrap[mut m] => {
+            let mut e = vec![Expr {
+                span: span!(),
+                node: Expr_::CallStatement(name, vec![])
+            }];
+            e.append(&mut m);
+            e
+        },
+        Ident(name) LParen call_args[args] RParen SemiColon exprwrap[mut m] => {
+            let mut e = vec![Expr {
+                span: span!(),
+                node: Expr_::CallStatement(name, args)
+            }];
+            e.append(&mut m);
+            e
+        }
+    }
+
+    // a single statement, as a call could not be told apart from the condition
+    for_init: Expr {
+        Variable Ident(name) Equals exprs[e] SemiColon => Expr {
+            span: span!(),
+            node: Expr_::Assign(name, Box::new(e[0].clone()))
+        },
+        Mutate Ident(name) Equals exprs[e] SemiColon => Expr {
+            span: span!(),
+            node: Expr_::ReAssign(name, Box::new(e[0].clone()))
         }
     }
 
+    label: Option<String> {
+        => None,
+        Label(l) Colon => Some(l)
+    }
+
+    target: Option<String> {
+        => None,
+        Label(l) => Some(l)
+    }
+
+    else_branch: Vec<Expr> {
+        => vec![],
+        Else LBrace expr[c] RBrace => c,
+        Else If term[c] LBrace expr[b] RBrace else_branch[e] => vec![Expr {
+            span: span!(),
+            node: Expr_::IfElse(Box::new(c), b, e)
+        }]
+    }
+
     exprs: Vec<Expr> {
         LBrace expr[e] RBrace => e,
         term[a] => vec![a],
@@ -230,35 +367,59 @@ plex::parser! {
             span: span!(),
             node: Expr_::Var(a)
         },
-        Byte(x) => Expr {
+        Int(v, ty) => Expr {
+            span: span!(),
+            node: Expr_::Int(v, ty)
+        },
+        True => Expr {
+            span: span!(),
+            node: Expr_::Bool(true)
+        },
+        False => Expr {
+            span: span!(),
+            node: Expr_::Bool(false)
+        },
+        Ident(name) LParen RParen => Expr {
             span: span!(),
-            node: Expr_::Byte(x)
+            node: Expr_::FunctionCall(name, vec![])
+        },
+        Ident(name) LParen call_args[args] RParen => Expr {
+            span: span!(),
+            node: Expr_::FunctionCall(name, args)
         },
         LParen term[a] RParen => a,
         Minus atom[b] => Expr {
             span: span!(),
             node: Expr_::Sub(Box::new(Expr {
                 span: span!(),
-                node: Expr_::Byte(0)
+                node: Expr_::Int(0, None)
             }), Box::new(b))
         },
     }
 
-    declare_args: Vec<String> {
-        Ident(arg) => vec![arg],
-        Bang => vec![String::from("void")],
+    declare_args: Vec<Type> {
+        Ident(arg) => vec![Type::from_name(&arg)],
+        Bang => vec![Type::Void],
         Ident(arg) Minus Gt declare_args[mut second_arg] => {
-            let mut arg = vec![arg];
+            let mut arg = vec![Type::from_name(&arg)];
             arg.append(&mut second_arg);
             arg
         }
         Bang Minus Gt declare_args[mut second_arg] => {
-            let mut arg = vec![String::from("void")];
+            let mut arg = vec![Type::Void];
             arg.append(&mut second_arg);
             arg
         }
     }
 
+    call_args: Vec<Expr> {
+        term[a] => vec![a],
+        call_args[mut args] Comma term[a] => {
+            args.push(a);
+            args
+        }
+    }
+
     define_args: Vec<String> {
         Ident(arg) => vec![arg],
         Ident(arg) define_args[mut second_arg] => {
@@ -269,8 +430,326 @@ plex::parser! {
     }
 }
 
-pub fn parse<I: Iterator<Item = (Token, Span)>>(
-    i: I,
-) -> Result<Program, (Option<(Token, Span)>, &'static str)> {
+/// The offending token (or `None` at end of input) and what was expected instead.
+pub type ParseError = (Option<(Token, Span)>, &'static str);
+
+pub fn parse<I: Iterator<Item = (Token, Span)>>(i: I) -> Result<Program, ParseError> {
     parse_(i)
 }
+
+/// Parses a whole token stream, resynchronising after each syntax error so that
+/// every error in the file is reported.
+///
+/// Top-level items are parsed one at a time, split at `;` outside of braces. When
+/// an item fails, the statement containing the offending token is dropped (up to
+/// the next `;` or the `}` closing its block) and the item is parsed again. The
+/// returned program holds everything that could still be parsed.
+///
+/// [`Token::Error`]s from the lexer are reported as errors of their own; unknown
+/// characters are then skipped and malformed integer literals parsed as `0`.
+pub fn parse_recovering<I: Iterator<Item = (Token, Span)>>(i: I) -> (Program, Vec<ParseError>) {
+    let mut stmts = vec![];
+    let mut errors: Vec<ParseError> = vec![];
+    let mut tokens = vec![];
+    for (tok, span) in i {
+        match tok {
+            Error(err) => {
+                errors.push((Some((Error(err), span)), "invalid token"));
+                // a malformed literal is still a literal as far as the grammar is concerned
+                if err != LexError::UnknownCharacter {
+                    tokens.push((Int(0, None), span));
+                }
+            }
+            tok => tokens.push((tok, span)),
+        }
+    }
+
+    for item in split_items(&tokens) {
+        let mut item = item.to_vec();
+        // every retry removes at least one token, or fills an empty block
+        for _ in 0..=item.len() * 2 {
+            match parse_(item.iter().cloned()) {
+                Ok(mut program) => {
+                    stmts.append(&mut program.stmts);
+                    break;
+                }
+                Err(err) => {
+                    let at = err.0.as_ref().and_then(|(_, span)| {
+                        item.iter()
+                            .position(|(_, s)| s.lo == span.lo && s.hi == span.hi)
+                    });
+                    errors.push(err);
+                    match at {
+                        Some(at) => skip_statement(&mut item, at),
+                        None => break,
+                    }
+                    if item.is_empty() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    errors.sort_by_key(|(tok, _)| tok.as_ref().map_or(usize::MAX, |(_, span)| span.lo));
+    (Program { stmts }, errors)
+}
+
+fn split_items(tokens: &[(Token, Span)]) -> Vec<&[(Token, Span)]> {
+    let mut items = vec![];
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (i, (tok, _)) in tokens.iter().enumerate() {
+        match tok {
+            LBrace => depth += 1,
+            RBrace => depth = depth.saturating_sub(1),
+            SemiColon if depth == 0 => {
+                items.push(&tokens[start..=i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < tokens.len() {
+        items.push(&tokens[start..]);
+    }
+    items
+}
+
+/// Removes the statement around `tokens[at]`, keeping the braces around it balanced.
+fn skip_statement(tokens: &mut Vec<(Token, Span)>, at: usize) {
+    let mut depth = 0usize;
+    let mut lo = at;
+    while lo > 0 {
+        match tokens[lo - 1].0 {
+            LBrace | SemiColon if depth == 0 => break,
+            LBrace => depth -= 1,
+            RBrace => depth += 1,
+            _ => {}
+        }
+        lo -= 1;
+    }
+
+    let mut depth = 0usize;
+    let mut hi = at;
+    while hi < tokens.len() {
+        match tokens[hi].0 {
+            RBrace if depth == 0 => break,
+            RBrace => depth -= 1,
+            LBrace => depth += 1,
+            SemiColon if depth == 0 => {
+                hi += 1;
+                break;
+            }
+            _ => {}
+        }
+        hi += 1;
+    }
+
+    let empty_block = |tokens: &Vec<(Token, Span)>, lo: usize| {
+        lo > 0 && matches!(tokens[lo - 1].0, LBrace) && matches!(tokens.get(lo), Some((RBrace, _)))
+    };
+    if lo == hi && !empty_block(tokens, lo) {
+        hi += 1;
+    }
+    tokens.drain(lo..hi);
+
+    // blocks need at least one statement
+    if empty_block(tokens, lo) {
+        let at = tokens[lo].1.lo;
+        let span = Span { lo: at, hi: at };
+        tokens.insert(lo, (SemiColon, span));
+        tokens.insert(lo, (Pass, span));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn tokens(src: &str) -> Vec<(Token, Span)> {
+        Lexer::new(src).collect()
+    }
+
+    fn parse(src: &str) -> (Program, Vec<ParseError>) {
+        parse_recovering(Lexer::new(src))
+    }
+
+    /// The body of the definition of `main` in `program`.
+    fn main_body(program: &Program) -> &[Expr] {
+        program
+            .stmts
+            .iter()
+            .find_map(|stmt| match &stmt.node {
+                Expr_::Define(name, _, body) if name == "main" => Some(body.as_slice()),
+                _ => None,
+            })
+            .expect("`main` is defined")
+    }
+
+    #[test]
+    fn split_items_at_top_level_semicolons() {
+        let tokens = tokens("declare main = i32; define main = { var x = 1; return x; }; pass");
+        let items = split_items(&tokens);
+        assert_eq!(items.len(), 3);
+        assert!(matches!(items[0].last(), Some((SemiColon, _))));
+        assert!(matches!(items[1].first(), Some((Define, _))));
+        assert!(matches!(items[1][items[1].len() - 2], (RBrace, _)));
+        assert!(matches!(items[2], [(Pass, _)]));
+    }
+
+    #[test]
+    fn skip_statement_drops_the_statement() {
+        let mut tokens = tokens("{ var x = 1; mutate x = ; return x; }");
+        let at = tokens
+            .iter()
+            .position(|(tok, _)| matches!(tok, Mutate))
+            .unwrap();
+        skip_statement(&mut tokens, at + 3);
+        let kept: Vec<_> = tokens.iter().map(|(tok, _)| format!("{:?}", tok)).collect();
+        assert_eq!(
+            kept.join(" "),
+            "LBrace Variable Ident(\"x\") Equals Int(1, None) SemiColon \
+             Return Ident(\"x\") SemiColon RBrace"
+        );
+    }
+
+    #[test]
+    fn skip_statement_fills_an_empty_block() {
+        let mut tokens = tokens("{ mutate x = ; }");
+        skip_statement(&mut tokens, 4);
+        assert!(matches!(
+            tokens.as_slice(),
+            [(LBrace, _), (Pass, _), (SemiColon, _), (RBrace, _)]
+        ));
+    }
+
+    #[test]
+    fn recovers_to_report_every_error() {
+        let (program, errors) = parse(
+            "declare main = i32;
+             define main = { var x = ; return 0i32; };
+             declare f = i32;
+             define f = { mutate = 1; return 1i32; };",
+        );
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].0.as_ref().unwrap().1.lo < errors[1].0.as_ref().unwrap().1.lo);
+        assert_eq!(program.stmts.len(), 4);
+        assert!(matches!(
+            main_body(&program),
+            [Expr {
+                node: Expr_::Int(0, _),
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn reports_the_end_of_input() {
+        let (_, errors) = parse("define main = { return 0i32;");
+        assert!(matches!(errors.as_slice(), [(None, _)]));
+    }
+
+    #[test]
+    fn reports_invalid_tokens() {
+        let (program, errors) = parse("declare main = i32; define main = 0x_i32 + 1i32 $;");
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(
+            errors[0].0,
+            Some((Error(LexError::InvalidInt), _))
+        ));
+        assert!(matches!(
+            errors[1].0,
+            Some((Error(LexError::UnknownCharacter), _))
+        ));
+        assert_eq!(program.stmts.len(), 2);
+    }
+
+    #[test]
+    fn calls_are_statements() {
+        let (program, errors) = parse("define main = { f(1); g(); return h(2, 3); };");
+        assert!(errors.is_empty());
+        assert!(matches!(
+            main_body(&program),
+            [
+                Expr {
+                    node: Expr_::CallStatement(..),
+                    ..
+                },
+                Expr {
+                    node: Expr_::CallStatement(..),
+                    ..
+                },
+                Expr {
+                    node: Expr_::FunctionCall(..),
+                    ..
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn else_if_chains_nest() {
+        let (program, errors) = parse(
+            "define main = {
+                if a { pass; } else if b { pass; } else { pass; }
+                if c { pass; }
+                return 0;
+            };",
+        );
+        assert!(errors.is_empty());
+        match main_body(&program) {
+            [Expr {
+                node: Expr_::IfElse(_, _, else_b),
+                ..
+            }, Expr {
+                node: Expr_::IfElse(_, _, no_else),
+                ..
+            }, _] => {
+                assert!(matches!(
+                    else_b.as_slice(),
+                    [Expr {
+                        node: Expr_::IfElse(..),
+                        ..
+                    }]
+                ));
+                assert!(no_else.is_empty());
+            }
+            body => panic!("unexpected body {:?}", body),
+        }
+    }
+
+    #[test]
+    fn loops_keep_their_labels() {
+        let (program, errors) = parse(
+            "define main = {
+                'outer: for var i = 0; i < 3; mutate i = i + 1; {
+                    while true { continue 'outer; }
+                }
+                loop { break; }
+                return 0;
+            };",
+        );
+        assert!(errors.is_empty());
+        match main_body(&program) {
+            [Expr {
+                node: Expr_::ForLoop(Some(label), _, _, _, body),
+                ..
+            }, Expr {
+                node: Expr_::Loop(None, _),
+                ..
+            }, _] => {
+                assert_eq!(label, "outer");
+                assert!(matches!(
+                    body.as_slice(),
+                    [Expr {
+                        node: Expr_::While(None, _, _),
+                        ..
+                    }]
+                ));
+            }
+            body => panic!("unexpected body {:?}", body),
+        }
+    }
+}
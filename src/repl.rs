@@ -0,0 +1,207 @@
+use std::io::{self, BufRead, Write};
+
+use crate::diagnostics::Diagnostic;
+use crate::lexer::{Span, Token};
+use crate::llvm::{compile_llvm, ExecutionEngine};
+use crate::parser::{self, Expr, Expr_, Program};
+use crate::session::{CompileError, Options, Session};
+use crate::typeck;
+use crate::types::{IntType, Type};
+
+/// An interactive session that JIT-compiles each input as it is entered.
+///
+/// Every input becomes a module of its own, added to one execution engine. Such a
+/// module redeclares all functions declared so far, so it can call the ones
+/// defined by earlier inputs. An expression is wrapped in a function returning
+/// it, which is called right away.
+pub struct Repl {
+    engine: ExecutionEngine,
+    /// Every declaration and definition entered so far.
+    items: Vec<Expr>,
+    evaluated: usize,
+}
+
+impl Repl {
+    pub fn new() -> Result<Repl, CompileError> {
+        let module = unsafe { compile_llvm(vec![], None) };
+        Ok(Repl {
+            engine: ExecutionEngine::new(module).map_err(CompileError::Llvm)?,
+            items: vec![],
+            evaluated: 0,
+        })
+    }
+
+    /// Evaluates one input, returning the value of an expression. Declarations
+    /// and definitions are kept for later inputs.
+    pub fn eval(&mut self, session: &Session) -> Result<Option<String>, CompileError> {
+        match session.tokens().first() {
+            Some((Token::Declare | Token::Define, _)) => self.add_items(session).map(|_| None),
+            Some(_) => self.eval_expr(session),
+            None => Ok(None),
+        }
+    }
+
+    fn add_items(&mut self, session: &Session) -> Result<(), CompileError> {
+        let new = session.parse()?.stmts;
+        let mut stmts = self.items.clone();
+        stmts.extend(new.iter().cloned());
+        session.check_items(&Program { stmts })?;
+
+        let module =
+            unsafe { compile_llvm(self.declarations().chain(new.clone()).collect(), None) };
+        module.verify().map_err(CompileError::Llvm)?;
+        self.engine.add_module(module);
+        self.items.extend(new);
+        Ok(())
+    }
+
+    fn eval_expr(&mut self, session: &Session) -> Result<Option<String>, CompileError> {
+        let name = format!("__repl_{}", self.evaluated);
+        let body = self.parse_expr(session, &name)?;
+        let expr = match body.as_slice() {
+            [expr] if !matches!(expr.node, Expr_::Pass) => expr.clone(),
+            _ => {
+                return Err(CompileError::Type(vec![Diagnostic::error(
+                    "expected an expression, a declaration or a definition",
+                )]))
+            }
+        };
+        let items = Program {
+            stmts: self.items.clone(),
+        };
+        let ty = typeck::infer(&items, &expr).map_err(CompileError::Type)?;
+        // the JIT cannot resolve a call to a function that is not defined yet
+        let undefined: Vec<Diagnostic> = typeck::undefined(&items)
+            .into_iter()
+            // the span points into the earlier input declaring the function
+            .map(|diagnostic| Diagnostic {
+                span: None,
+                label: None,
+                ..diagnostic
+            })
+            .collect();
+        if !undefined.is_empty() {
+            return Err(CompileError::Type(undefined));
+        }
+
+        let span = expr.span;
+        let function = [
+            Expr {
+                span,
+                node: Expr_::Declare(name.clone(), vec![Type::Void, ty.clone()], None),
+            },
+            Expr {
+                span,
+                node: Expr_::Define(name.clone(), vec![], vec![expr]),
+            },
+        ];
+        let module = unsafe { compile_llvm(self.declarations().chain(function).collect(), None) };
+        module.verify().map_err(CompileError::Llvm)?;
+        self.engine.add_module(module);
+        self.evaluated += 1;
+
+        let value = unsafe { self.engine.call(&name, &ty) }.map_err(CompileError::Llvm)?;
+        Ok(match ty {
+            Type::Void => None,
+            Type::Bool => Some(format!("{}: bool", value != 0)),
+            Type::Int(IntType::U64) => Some(format!("{}: u64", value as u64)),
+            ty => Some(format!("{}: {}", value, ty.name())),
+        })
+    }
+
+    /// Parses the input as the body of a function called `name`.
+    fn parse_expr(&self, session: &Session, name: &str) -> Result<Vec<Expr>, CompileError> {
+        let eof = session.file().eof_span();
+        let mut tokens = session.tokens();
+        if let Some((Token::SemiColon, _)) = tokens.last() {
+            tokens.pop();
+        }
+        let start = Span { lo: 0, hi: 0 };
+        let wrapped = [
+            (Token::Define, start),
+            (Token::Ident(name.to_owned()), start),
+            (Token::Equals, start),
+        ]
+        .into_iter()
+        .chain(tokens)
+        .chain([(Token::SemiColon, eof)]);
+
+        match parser::parse(wrapped) {
+            Ok(mut program) => match program.stmts.pop().map(|item| item.node) {
+                Some(Expr_::Define(_, _, body)) => Ok(body),
+                _ => unreachable!("the input is wrapped in a definition"),
+            },
+            // the `;` closing the definition is not part of the input
+            Err((Some((Token::SemiColon, span)), expected)) if span.lo == eof.lo => {
+                Err(CompileError::Parse(vec![(None, expected)]))
+            }
+            Err(err) => Err(CompileError::Parse(vec![err])),
+        }
+    }
+
+    /// The declarations entered so far, so a new module can refer to their functions.
+    fn declarations(&self) -> impl Iterator<Item = Expr> + '_ {
+        self.items
+            .iter()
+            .filter(|item| matches!(item.node, Expr_::Declare(..)))
+            .cloned()
+    }
+}
+
+/// Reads inputs from stdin until it is closed, printing the value of each
+/// expression. An input continues over several lines until it is complete.
+pub fn run() -> Result<(), CompileError> {
+    let mut repl = Repl::new()?;
+    let stdin = io::stdin();
+    let mut input = String::new();
+    loop {
+        print!("{}", if input.is_empty() { ">> " } else { ".. " });
+        io::stdout().flush()?;
+        if stdin.lock().read_line(&mut input)? == 0 {
+            println!();
+            return Ok(());
+        }
+        if input.trim().is_empty() {
+            input.clear();
+            continue;
+        }
+
+        let session = Session::new(input.clone(), Options::default());
+        match repl.eval(&session) {
+            Ok(Some(value)) => println!("{}", value),
+            Ok(None) => {}
+            Err(CompileError::Parse(errors)) if errors.iter().any(|(tok, _)| tok.is_none()) => {
+                continue
+            }
+            Err(err) => eprint!("{}", session.report(&err)),
+        }
+        input.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::PoisonError;
+
+    use super::*;
+    use crate::llvm::GLOBAL_CONTEXT;
+
+    /// Evaluates `input`, returning its value or the rendered error.
+    fn eval(repl: &mut Repl, input: &str) -> Result<Option<String>, String> {
+        let session = Session::new(input, Options::default());
+        repl.eval(&session).map_err(|err| session.report(&err))
+    }
+
+    #[test]
+    fn functions_can_be_defined_after_their_declaration() {
+        let _llvm = GLOBAL_CONTEXT
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut repl = Repl::new().unwrap();
+        assert_eq!(eval(&mut repl, "declare f = i32 -> i32;"), Ok(None));
+        let err = eval(&mut repl, "f(1i32)").unwrap_err();
+        assert!(err.contains("`f` is declared but never defined"), "{}", err);
+        assert_eq!(eval(&mut repl, "define f x = x + 1i32;"), Ok(None));
+        assert_eq!(eval(&mut repl, "f(1i32)"), Ok(Some(String::from("2: i32"))));
+    }
+}
@@ -0,0 +1,596 @@
+use std::cell::{OnceCell, RefCell};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, info_span, instrument};
+
+use crate::diagnostics::{Diagnostic, SourceFile};
+use crate::lexer::{self, LexError, Lexer, Span, Token};
+use crate::linker::Linker;
+use crate::llvm::{self, compile_llvm, ExecutionEngine, FileType, Module, OptLevel, TargetMachine};
+use crate::parser::{self, Expr_, ParseError, Program};
+use crate::typeck;
+use crate::types::Type;
+
+/// Options controlling how a [`Session`] builds its output.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub opt_level: OptLevel,
+    /// Whether to emit DWARF debug info.
+    pub debug_info: bool,
+    /// The triple to compile for, or `None` for the host.
+    pub target: Option<String>,
+    pub r#static: bool,
+    pub strip: bool,
+    /// The linker to use instead of the first of `cc`, `clang` or `ld.lld` found.
+    pub linker: Option<PathBuf>,
+    /// Libraries linked against, as with `-l`.
+    pub libraries: Vec<String>,
+    /// Directories searched for libraries, as with `-L`.
+    pub library_paths: Vec<PathBuf>,
+    /// Extra arguments passed to the linker as they are.
+    pub link_args: Vec<String>,
+}
+
+/// An artifact the driver can write, as named by `--emit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmitKind {
+    Tokens,
+    Ast,
+    LlvmIr,
+    LlvmBc,
+    Asm,
+    Obj,
+    Link,
+}
+
+impl EmitKind {
+    pub const ALL: [EmitKind; 7] = [
+        EmitKind::Tokens,
+        EmitKind::Ast,
+        EmitKind::LlvmIr,
+        EmitKind::LlvmBc,
+        EmitKind::Asm,
+        EmitKind::Obj,
+        EmitKind::Link,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EmitKind::Tokens => "tokens",
+            EmitKind::Ast => "ast",
+            EmitKind::LlvmIr => "llvm-ir",
+            EmitKind::LlvmBc => "llvm-bc",
+            EmitKind::Asm => "asm",
+            EmitKind::Obj => "obj",
+            EmitKind::Link => "link",
+        }
+    }
+
+    /// The extension of the artifact's file, or `None` for the executable.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            EmitKind::Tokens => Some("tokens"),
+            EmitKind::Ast => Some("ast"),
+            EmitKind::LlvmIr => Some("ll"),
+            EmitKind::LlvmBc => Some("bc"),
+            EmitKind::Asm => Some("s"),
+            EmitKind::Obj => Some("o"),
+            EmitKind::Link => None,
+        }
+    }
+
+    /// Whether producing this artifact needs the program to be lowered to LLVM.
+    pub fn needs_codegen(self) -> bool {
+        !matches!(self, EmitKind::Tokens | EmitKind::Ast)
+    }
+}
+
+impl FromStr for EmitKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EmitKind::ALL
+            .into_iter()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| {
+                let names = EmitKind::ALL.map(EmitKind::name);
+                format!("expected one of {}", names.join(", "))
+            })
+    }
+}
+
+/// Everything that can stop a compilation, each with its own process exit code.
+#[derive(Debug)]
+pub enum CompileError {
+    /// The input file could not be read.
+    Input(PathBuf, io::Error),
+    Parse(Vec<ParseError>),
+    Type(Vec<Diagnostic>),
+    Io(io::Error),
+    /// An error reported by LLVM itself, e.g. for an unknown target.
+    Llvm(String),
+    /// None of the linkers tried is installed, with the triple when they were
+    /// looked for to cross-compile.
+    LinkerNotFound {
+        cross: Option<String>,
+        tried: Vec<String>,
+    },
+    /// The linker could not be set up, e.g. for lack of a C runtime.
+    Link(String),
+    /// An external tool like `ld.lld` could not be started.
+    ToolNotFound(String, io::Error),
+    /// An external tool exited unsuccessfully, with what it wrote to stderr.
+    ToolFailed {
+        tool: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+}
+
+impl CompileError {
+    /// The exit code the driver ends with, distinguishing usage (`2`, as for bad
+    /// arguments), syntax (`3`), type (`4`) and link (`5`) errors from any other
+    /// failure (`1`).
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CompileError::Input(..) => 2,
+            CompileError::Parse(_) => 3,
+            CompileError::Type(_) => 4,
+            CompileError::LinkerNotFound { .. }
+            | CompileError::Link(_)
+            | CompileError::ToolNotFound(..)
+            | CompileError::ToolFailed { .. } => 5,
+            CompileError::Io(_) | CompileError::Llvm(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::Input(path, err) => {
+                write!(f, "could not read `{}`: {}", path.display(), err)
+            }
+            CompileError::Parse(errors) => write!(f, "{} syntax error(s)", errors.len()),
+            CompileError::Type(errors) => write!(f, "{} type error(s)", errors.len()),
+            CompileError::Io(err) => write!(f, "{}", err),
+            CompileError::Llvm(msg) => write!(f, "{}", msg),
+            CompileError::LinkerNotFound { cross, tried } => {
+                let tried: Vec<_> = tried.iter().map(|tool| format!("`{}`", tool)).collect();
+                match cross {
+                    Some(triple) => write!(f, "no cross linker for `{}` found", triple)?,
+                    None => write!(f, "no linker found")?,
+                }
+                write!(f, ", tried {}", tried.join(", "))
+            }
+            CompileError::Link(msg) => write!(f, "{}", msg),
+            CompileError::ToolNotFound(tool, err) => write!(f, "could not run `{}`: {}", tool, err),
+            CompileError::ToolFailed { tool, status, .. } => {
+                write!(f, "`{}` failed with {}", tool, status)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+impl From<io::Error> for CompileError {
+    fn from(err: io::Error) -> Self {
+        CompileError::Io(err)
+    }
+}
+
+/// A program that passed [`Session::check`], which is all [`codegen`](Session::codegen)
+/// and [`run`](Session::run) accept, as `compile_llvm` assumes a well-typed program.
+#[derive(Debug)]
+pub struct CheckedProgram(Program);
+
+impl CheckedProgram {
+    pub fn program(&self) -> &Program {
+        &self.0
+    }
+}
+
+/// A single compilation of one Sugar source file.
+///
+/// Each stage can be requested on its own: [`tokens`](Session::tokens),
+/// [`parse`](Session::parse), [`codegen`](Session::codegen) and
+/// [`emit_object`](Session::emit_object), with [`link`](Session::link) turning
+/// an object file into an executable. The wall time of each of these passes is
+/// recorded, see [`pass_times`](Session::pass_times).
+pub struct Session {
+    file: SourceFile,
+    options: Options,
+    passes: RefCell<Vec<(&'static str, Duration)>>,
+    /// The source's tokens, once they were needed.
+    tokens: OnceCell<Vec<(Token, Span)>>,
+}
+
+impl Session {
+    pub fn new(source: impl Into<String>, options: Options) -> Session {
+        Session {
+            file: SourceFile::new("<input>", source),
+            options,
+            passes: RefCell::default(),
+            tokens: OnceCell::new(),
+        }
+    }
+
+    /// Reads the source file at `path`, which is also used to name it in diagnostics.
+    pub fn from_path(path: impl AsRef<Path>, options: Options) -> Result<Session, CompileError> {
+        let path = path.as_ref();
+        let source =
+            fs::read_to_string(path).map_err(|err| CompileError::Input(path.to_owned(), err))?;
+        Ok(Session {
+            file: SourceFile::new(path.display().to_string(), source),
+            options,
+            passes: RefCell::default(),
+            tokens: OnceCell::new(),
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        self.file.src()
+    }
+
+    pub fn file(&self) -> &SourceFile {
+        &self.file
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// How long each pass run so far took, in the order they ran.
+    pub fn pass_times(&self) -> Vec<(&'static str, Duration)> {
+        self.passes.borrow().clone()
+    }
+
+    fn time<T>(&self, pass: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.passes.borrow_mut().push((pass, start.elapsed()));
+        result
+    }
+
+    /// The source's tokens. It is only lexed the first time they are asked for.
+    pub fn tokens(&self) -> Vec<(Token, Span)> {
+        self.tokens.get_or_init(|| self.lex()).clone()
+    }
+
+    #[instrument(name = "lex", skip_all)]
+    fn lex(&self) -> Vec<(Token, Span)> {
+        self.time("lex", || Lexer::new(self.file.src()).collect())
+    }
+
+    /// Parses the source, failing if there was any syntax error.
+    pub fn parse(&self) -> Result<Program, CompileError> {
+        match self.parse_partial() {
+            (program, errors) if errors.is_empty() => Ok(program),
+            (_, errors) => Err(CompileError::Parse(errors)),
+        }
+    }
+
+    /// Parses as much of the source as possible, returning every syntax error
+    /// alongside whatever could still be parsed.
+    #[instrument(name = "parse", skip_all)]
+    pub fn parse_partial(&self) -> (Program, Vec<ParseError>) {
+        let tokens = self.tokens();
+        self.time("parse", || parser::parse_recovering(tokens.into_iter()))
+    }
+
+    /// Converts an error from this session into diagnostics pointing into its source.
+    pub fn diagnostics(&self, err: &CompileError) -> Vec<Diagnostic> {
+        match err {
+            CompileError::Parse(errors) => errors
+                .iter()
+                .map(|err| self.parse_diagnostic(err))
+                .collect(),
+            CompileError::Type(errors) => errors.clone(),
+            CompileError::LinkerNotFound { cross: Some(_), .. } => {
+                vec![Diagnostic::error(err.to_string()).with_note(
+                    "install `clang` or `ld.lld`, or pass a linker for the target with `--linker`",
+                )]
+            }
+            CompileError::LinkerNotFound { .. } | CompileError::Link(_) => {
+                vec![Diagnostic::error(err.to_string())
+                    .with_note("install `cc` or `clang`, or pass another linker with `--linker`")]
+            }
+            CompileError::ToolFailed { stderr, .. } => {
+                let diagnostic = Diagnostic::error(err.to_string());
+                vec![stderr
+                    .lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .fold(diagnostic, |d, line| d.with_note(line))]
+            }
+            err => vec![Diagnostic::error(err.to_string())],
+        }
+    }
+
+    pub fn parse_diagnostic(&self, err: &ParseError) -> Diagnostic {
+        match err {
+            (Some((Token::Error(err), span)), _) => {
+                let snippet = self.file.snippet(*span);
+                let (message, label) = match err {
+                    LexError::UnknownCharacter => (
+                        format!("unknown character `{}`", snippet),
+                        "no token starts with this character",
+                    ),
+                    LexError::InvalidInt => (
+                        format!("invalid integer literal `{}`", snippet),
+                        "no digits after the prefix",
+                    ),
+                    LexError::InvalidSuffix => (
+                        format!("invalid suffix on integer literal `{}`", snippet),
+                        "expected one of `i8`, `i16`, `i32`, `i64`, `u8`, `u16`, `u32` or `u64`",
+                    ),
+                    LexError::IntTooLarge => (
+                        String::from("integer literal is too large"),
+                        "does not fit in 128 bits",
+                    ),
+                };
+                Diagnostic::error(message)
+                    .with_span(*span)
+                    .with_label(label)
+            }
+            (Some((_, span)), expected) => {
+                Diagnostic::error(format!("unexpected `{}`", self.file.snippet(*span)))
+                    .with_span(*span)
+                    .with_label(spell_expected(expected))
+            }
+            (None, expected) => Diagnostic::error("unexpected end of file")
+                .with_span(self.file.eof_span())
+                .with_label(spell_expected(expected)),
+        }
+    }
+
+    /// Renders `err` against this session's source, ready to print.
+    pub fn report(&self, err: &CompileError) -> String {
+        let diagnostics = self.diagnostics(err);
+        let mut out = diagnostics
+            .iter()
+            .map(|d| d.render(&self.file))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if diagnostics.len() > 1 {
+            out.push_str(&format!(
+                "\nerror: aborting due to {} previous errors\n",
+                diagnostics.len()
+            ));
+        }
+        out
+    }
+
+    /// Lowers a checked program to LLVM IR, verifies the result and optimizes
+    /// it at the session's `-O` level.
+    #[instrument(skip_all)]
+    pub fn codegen(&self, program: CheckedProgram) -> Result<Module, CompileError> {
+        let machine = self.target_machine()?;
+        let module = self.time("codegen", || {
+            let source = self.options.debug_info.then_some(&self.file);
+            unsafe { compile_llvm(program.0.stmts, source) }
+        });
+        machine.configure(&module);
+        self.time("verify", || module.verify())
+            .map_err(CompileError::Llvm)?;
+        self.optimize(&module, &machine)?;
+        Ok(module)
+    }
+
+    #[instrument(skip_all, fields(level = self.options.opt_level.name()))]
+    fn optimize(&self, module: &Module, machine: &TargetMachine) -> Result<(), CompileError> {
+        let level = self.options.opt_level;
+        self.time("optimize", || module.optimize(machine, level))
+            .map_err(CompileError::Llvm)
+    }
+
+    /// The normalized triple this session compiles for.
+    pub fn target_triple(&self) -> String {
+        match &self.options.target {
+            Some(triple) => llvm::normalize_triple(triple),
+            None => llvm::host_triple(),
+        }
+    }
+
+    /// Whether `--target` names another machine than this one. Vendors are not
+    /// compared, so `x86_64-linux-gnu` is the host `x86_64-pc-linux-gnu`.
+    pub fn cross_compiling(&self) -> bool {
+        let without_vendor = |triple: String| {
+            let mut parts: Vec<String> = triple.split('-').map(str::to_owned).collect();
+            if parts.len() > 2 {
+                parts.remove(1);
+            }
+            parts
+        };
+        self.options.target.is_some()
+            && without_vendor(self.target_triple()) != without_vendor(llvm::host_triple())
+    }
+
+    /// A target machine for `--target`, or one tuned for the host CPU without it.
+    fn target_machine(&self) -> Result<TargetMachine, CompileError> {
+        let level = self.options.opt_level;
+        match &self.options.target {
+            Some(_) => TargetMachine::new(&self.target_triple(), "generic", "", level),
+            None => TargetMachine::host(level),
+        }
+        .map_err(CompileError::Llvm)
+    }
+
+    /// Type checks a parsed program before it is handed to [`codegen`](Session::codegen).
+    #[instrument(name = "typeck", skip_all)]
+    pub fn check(&self, program: Program) -> Result<CheckedProgram, CompileError> {
+        let errors = self.time("typeck", || typeck::check(&program));
+        if errors.is_empty() {
+            Ok(CheckedProgram(program))
+        } else {
+            Err(CompileError::Type(errors))
+        }
+    }
+
+    /// Type checks items that are not a whole program, so functions they declare
+    /// may still be defined later, as in the REPL.
+    #[instrument(name = "typeck", skip_all)]
+    pub fn check_items(&self, program: &Program) -> Result<(), CompileError> {
+        let errors = self.time("typeck", || typeck::check_items(program));
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(CompileError::Type(errors))
+        }
+    }
+
+    /// Parses and lowers the source, returning the module as textual LLVM IR.
+    pub fn llvm_ir(&self) -> Result<String, CompileError> {
+        let program = self.check(self.parse()?)?;
+        Ok(self.codegen(program)?.print_to_string())
+    }
+
+    /// Writes `module` as an object file for the host at `path`.
+    pub fn emit_object(&self, module: &Module, path: &Path) -> Result<(), CompileError> {
+        self.emit_file(module, path, FileType::Object)
+    }
+
+    /// Writes `module` as assembly for the host at `path`.
+    pub fn emit_assembly(&self, module: &Module, path: &Path) -> Result<(), CompileError> {
+        self.emit_file(module, path, FileType::Assembly)
+    }
+
+    /// Writes `module` as LLVM bitcode at `path`.
+    #[instrument(skip_all, fields(path = %path.display()))]
+    pub fn emit_bitcode(&self, module: &Module, path: &Path) -> Result<(), CompileError> {
+        if self.time("emit-bc", || module.write_bitcode(path)) {
+            Ok(())
+        } else {
+            Err(CompileError::Llvm(format!(
+                "could not write bitcode to {}",
+                path.display()
+            )))
+        }
+    }
+
+    #[instrument(name = "emit", skip_all, fields(kind = ?file_type, path = %path.display()))]
+    fn emit_file(
+        &self,
+        module: &Module,
+        path: &Path,
+        file_type: FileType,
+    ) -> Result<(), CompileError> {
+        let pass = match file_type {
+            FileType::Object => "emit-obj",
+            FileType::Assembly => "emit-asm",
+        };
+        let machine = self.target_machine()?;
+        self.time(pass, || machine.emit_to_file(module, path, file_type))
+            .map_err(CompileError::Llvm)
+    }
+
+    /// JIT-compiles a checked program and runs its `main`, returning the value
+    /// it returns (`0` if it returns nothing).
+    #[instrument(skip_all)]
+    pub fn run(&self, program: CheckedProgram) -> Result<i64, CompileError> {
+        if self.cross_compiling() {
+            return Err(CompileError::Llvm(format!(
+                "cannot run code compiled for `{}` on this machine",
+                self.target_triple()
+            )));
+        }
+        let ret = program
+            .0
+            .stmts
+            .iter()
+            .find_map(|expr| match &expr.node {
+                Expr_::Declare(name, types, _) if name == "main" => types.split_last(),
+                _ => None,
+            })
+            .filter(|(_, params)| params.iter().all(|ty| *ty == Type::Void))
+            .map(|(ret, _)| ret.clone());
+        let ret = match ret {
+            Some(ret) => ret,
+            None => {
+                return Err(CompileError::Type(vec![Diagnostic::error(
+                    "`main` function not found",
+                )
+                .with_note(
+                    "declare it as `declare main = ! -> <type>;` to run the program",
+                )]))
+            }
+        };
+        let defined = program
+            .0
+            .stmts
+            .iter()
+            .any(|expr| matches!(&expr.node, Expr_::Define(name, ..) if name == "main"));
+        if !defined {
+            return Err(CompileError::Type(vec![Diagnostic::error(
+                "`main` is declared but never defined",
+            )
+            .with_note("define it as `define main = ...;` to run the program")]));
+        }
+        let engine = ExecutionEngine::new(self.codegen(program)?).map_err(CompileError::Llvm)?;
+        let main = self.time("jit", || engine.function_address("main"));
+        if main.is_none() {
+            return Err(CompileError::Llvm(String::from("`main` was not compiled")));
+        }
+        unsafe { engine.call("main", &ret) }.map_err(CompileError::Llvm)
+    }
+
+    /// Links `object` with the C runtime and libc into an executable at
+    /// `output`, so that `main`'s return value becomes its exit code.
+    #[instrument(skip_all, fields(output = %output.display()))]
+    pub fn link(&self, object: &Path, output: &Path) -> Result<(), CompileError> {
+        let triple = self.target_triple();
+        let linker = Linker::find(
+            self.options.linker.as_deref(),
+            &triple,
+            self.cross_compiling(),
+        )?;
+        debug!(linker = %linker.program.display(), flavor = ?linker.flavor);
+        let mut command = linker.command(&self.options, &triple, object, output)?;
+        self.time("link", || run_tool(&mut command))?;
+
+        if self.options.strip {
+            self.time("strip", || run_tool(Command::new("strip").arg(output)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the token names in a parser's "expected ..." message with how the
+/// tokens are written, e.g. `` `LBrace` `` with `` `{` ``.
+fn spell_expected(expected: &str) -> String {
+    expected
+        .split('`')
+        .enumerate()
+        // every odd part was between backticks
+        .map(|(i, part)| match (i % 2, lexer::describe_token(part)) {
+            (1, Some(spelling)) => spelling.to_owned(),
+            (1, None) => format!("`{}`", part),
+            _ => part.to_owned(),
+        })
+        .collect()
+}
+
+/// Runs an external tool to completion, failing with its stderr unless it succeeds.
+fn run_tool(command: &mut Command) -> Result<Output, CompileError> {
+    let tool = command.get_program().to_string_lossy().into_owned();
+    let _span = info_span!("tool", %tool).entered();
+    debug!(?command, "running");
+    let output = command
+        .output()
+        .map_err(|err| CompileError::ToolNotFound(tool.clone(), err))?;
+    debug!(status = %output.status, "finished");
+    if !output.status.success() {
+        return Err(CompileError::ToolFailed {
+            tool,
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+    Ok(output)
+}
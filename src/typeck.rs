@@ -0,0 +1,803 @@
+use std::collections::HashMap;
+
+use crate::diagnostics::Diagnostic;
+use crate::lexer::Span;
+use crate::parser::{Expr, Expr_, Program};
+use crate::types::{is_untyped, IntType, Type};
+
+/// A function's parameter types and return type, from its `declare`.
+#[derive(Debug, Clone)]
+struct Signature {
+    params: Vec<Type>,
+    ret: Type,
+    /// Declared `extern`, so defined outside of Sugar.
+    external: bool,
+}
+
+/// Type checks a parsed program, returning every error found.
+///
+/// This runs between parsing and codegen, so anything it accepts must be
+/// something `compile_llvm` can lower. Literal types are inferred the same way
+/// codegen does: from a suffix, else from the other operand or the expected
+/// type, else `byte`.
+pub fn check(program: &Program) -> Vec<Diagnostic> {
+    let mut errors = check_items(program);
+    errors.extend(undefined(program));
+    errors
+}
+
+/// Type checks the items of a program that may not be complete yet, like the
+/// inputs to the REPL so far, so a declared function need not be defined.
+pub fn check_items(program: &Program) -> Vec<Diagnostic> {
+    let mut checker = Checker::default();
+    checker.declare_all(program);
+    for stmt in &program.stmts {
+        if let Expr_::Define(name, args, body) = &stmt.node {
+            checker.define(name, args, body, stmt.span);
+        }
+    }
+    checker.errors
+}
+
+/// Reports the functions declared without `extern` that are never defined,
+/// as nothing would provide them.
+pub fn undefined(program: &Program) -> Vec<Diagnostic> {
+    let defined: Vec<&str> = program
+        .stmts
+        .iter()
+        .filter_map(|stmt| match &stmt.node {
+            Expr_::Define(name, ..) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    program
+        .stmts
+        .iter()
+        .filter_map(|stmt| match &stmt.node {
+            Expr_::Declare(name, _, None) if !defined.contains(&name.as_str()) => Some(
+                Diagnostic::error(format!("`{}` is declared but never defined", name))
+                    .with_span(stmt.span)
+                    .with_label("declared here")
+                    .with_note(format!(
+                        "add a `define {}`, or declare it `extern \"C\"` if C code defines it",
+                        name
+                    )),
+            ),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Infers the type of a standalone expression, which may call any function
+/// declared in `program`.
+pub fn infer(program: &Program, expr: &Expr) -> Result<Type, Vec<Diagnostic>> {
+    let mut checker = Checker::default();
+    checker.declare_all(program);
+    // the program itself was already checked
+    checker.errors.clear();
+    match checker.expr(expr, None) {
+        Some(ty) if checker.errors.is_empty() => Ok(ty),
+        _ => Err(checker.errors),
+    }
+}
+
+#[derive(Default)]
+struct Checker {
+    functions: HashMap<String, Signature>,
+    defined: HashMap<String, Span>,
+    /// Variables in scope in the function being checked. Like in codegen, a
+    /// variable goes out of scope at the end of the block declaring it.
+    variables: HashMap<String, Type>,
+    /// Labels of the loops around the statement being checked, innermost last.
+    loops: Vec<Option<String>>,
+    errors: Vec<Diagnostic>,
+}
+
+impl Checker {
+    fn error(&mut self, diagnostic: Diagnostic) {
+        self.errors.push(diagnostic);
+    }
+
+    fn declare_all(&mut self, program: &Program) {
+        for stmt in &program.stmts {
+            if let Expr_::Declare(name, types, abi) = &stmt.node {
+                self.declare(name, types, abi.as_deref(), stmt.span);
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &str, types: &[Type], abi: Option<&str>, span: Span) {
+        if let Some(abi) = abi.filter(|abi| *abi != "C") {
+            self.error(
+                Diagnostic::error(format!("unsupported ABI `\"{}\"`", abi))
+                    .with_span(span)
+                    .with_label("only `extern \"C\"` is supported"),
+            );
+        }
+        for ty in types {
+            if let Type::Named(ty) = ty {
+                self.error(
+                    Diagnostic::error(format!("unknown type `{}`", ty))
+                        .with_span(span)
+                        .with_label(format!("in the declaration of `{}`", name))
+                        .with_note("expected `byte`, `bool`, `!` or an integer type like `i32`"),
+                );
+            }
+        }
+        let (ret, params) = types.split_last().unwrap();
+        if params.len() > 1 && params.contains(&Type::Void) {
+            self.error(
+                Diagnostic::error("`!` cannot be used as a parameter type")
+                    .with_span(span)
+                    .with_label(format!("in the declaration of `{}`", name))
+                    .with_note("write `declare f = ! -> ...` for a function without parameters"),
+            );
+        }
+        if self.functions.contains_key(name) {
+            self.error(
+                Diagnostic::error(format!("`{}` is declared more than once", name))
+                    .with_span(span)
+                    .with_label("declared again here"),
+            );
+            return;
+        }
+        self.functions.insert(
+            name.to_owned(),
+            Signature {
+                params: params
+                    .iter()
+                    .filter(|ty| **ty != Type::Void)
+                    .cloned()
+                    .collect(),
+                ret: ret.clone(),
+                external: abi.is_some(),
+            },
+        );
+    }
+
+    fn define(&mut self, name: &str, args: &[String], body: &[Expr], span: Span) {
+        let sig = match self.functions.get(name) {
+            Some(sig) => sig.clone(),
+            None => {
+                self.error(
+                    Diagnostic::error(format!("`{}` is defined without a declaration", name))
+                        .with_span(span)
+                        .with_note(format!(
+                            "add a declaration like `declare {} = ! -> byte;` before it",
+                            name
+                        )),
+                );
+                return;
+            }
+        };
+        if sig.external {
+            self.error(
+                Diagnostic::error(format!(
+                    "`{}` is declared `extern` and cannot be defined",
+                    name
+                ))
+                .with_span(span)
+                .with_note("remove `extern` to define the function in Sugar"),
+            );
+            return;
+        }
+        if self.defined.insert(name.to_owned(), span).is_some() {
+            self.error(
+                Diagnostic::error(format!("`{}` is defined more than once", name))
+                    .with_span(span)
+                    .with_label("defined again here"),
+            );
+            return;
+        }
+        if sig
+            .params
+            .iter()
+            .chain([&sig.ret])
+            .any(|ty| matches!(ty, Type::Named(_)))
+        {
+            // already reported with the declaration
+            return;
+        }
+
+        if args.len() != sig.params.len() {
+            self.error(
+                Diagnostic::error(format!(
+                    "`{}` takes {} parameter(s) but its definition binds {}",
+                    name,
+                    sig.params.len(),
+                    args.len()
+                ))
+                .with_span(span)
+                .with_label(format!(
+                    "expected {} name(s) after `define {}`",
+                    sig.params.len(),
+                    name
+                )),
+            );
+            return;
+        }
+
+        self.variables.clear();
+        for (arg, ty) in args.iter().zip(&sig.params) {
+            if self.variables.insert(arg.clone(), ty.clone()).is_some() {
+                self.error(
+                    Diagnostic::error(format!("parameter `{}` is bound more than once", arg))
+                        .with_span(span),
+                );
+            }
+        }
+        // a single-expression body is a `return` like any other value in statement position
+        self.statements(body, &sig.ret);
+        if sig.ret != Type::Void && !returns(body) {
+            self.error(
+                Diagnostic::error(format!("`{}` may not return a value", name))
+                    .with_span(span)
+                    .with_label(format!("expected to return `{}`", sig.ret.name()))
+                    .with_note("end the body with a `return`"),
+            );
+        }
+    }
+
+    fn statements(&mut self, stmts: &[Expr], ret: &Type) {
+        let outer = self.variables.clone();
+        // codegen skips anything after a `return`, `break` or `continue`, so it is not checked either
+        for stmt in reachable(stmts) {
+            match &stmt.node {
+                Expr_::Assign(..) | Expr_::ReAssign(..) => {
+                    self.expr(stmt, None);
+                }
+                Expr_::IfElse(cond, if_b, else_b) => {
+                    self.condition(cond);
+                    self.statements(if_b, ret);
+                    self.statements(else_b, ret);
+                }
+                Expr_::ForLoop(label, init, comp, run, block) => {
+                    // the counter is only in scope in the loop
+                    let outer = self.variables.clone();
+                    self.expr(init, None);
+                    self.condition(comp);
+                    self.loop_body(label, block, ret);
+                    self.expr(run, None);
+                    self.variables = outer;
+                }
+                Expr_::While(label, cond, block) => {
+                    self.condition(cond);
+                    self.loop_body(label, block, ret);
+                }
+                Expr_::Loop(label, block) => self.loop_body(label, block, ret),
+                // the value of a call statement, if any, is dropped
+                Expr_::CallStatement(name, args) => {
+                    self.call(name, args, stmt.span);
+                }
+                Expr_::Break(label) => self.jump("break", label, stmt.span),
+                Expr_::Continue(label) => self.jump("continue", label, stmt.span),
+                Expr_::Pass => {}
+                Expr_::Declare(..) | Expr_::Define(..) => self.error(
+                    Diagnostic::error("functions cannot be nested")
+                        .with_span(stmt.span)
+                        .with_label("move this to the top level"),
+                ),
+                _ => self.returned(stmt, ret),
+            }
+        }
+        self.variables = outer;
+    }
+
+    fn loop_body(&mut self, label: &Option<String>, block: &[Expr], ret: &Type) {
+        self.loops.push(label.clone());
+        self.statements(block, ret);
+        self.loops.pop();
+    }
+
+    /// Checks that a `break` or `continue` has a loop to jump out of.
+    fn jump(&mut self, keyword: &str, label: &Option<String>, span: Span) {
+        match label {
+            None if self.loops.is_empty() => self.error(
+                Diagnostic::error(format!("`{}` outside of a loop", keyword))
+                    .with_span(span)
+                    .with_label(format!("cannot `{}` here", keyword)),
+            ),
+            Some(label) if !self.loops.contains(&Some(label.clone())) => self.error(
+                Diagnostic::error(format!("use of undeclared label `'{}`", label))
+                    .with_span(span)
+                    .with_label("no enclosing loop has this label"),
+            ),
+            _ => {}
+        }
+    }
+
+    fn returned(&mut self, expr: &Expr, ret: &Type) {
+        if *ret == Type::Void {
+            // a call to a function returning `!` returns nothing either
+            if let Expr_::FunctionCall(name, args) = &expr.node {
+                if matches!(self.call(name, args, expr.span), Some(Type::Void) | None) {
+                    return;
+                }
+            }
+            self.error(
+                Diagnostic::error("cannot return a value from a function returning `!`")
+                    .with_span(expr.span),
+            );
+            return;
+        }
+        if let Some(ty) = self.expr(expr, Some(ret)) {
+            self.expect(ret, &ty, expr.span);
+        }
+    }
+
+    fn condition(&mut self, cond: &Expr) {
+        if let Some(ty) = self.expr(cond, None) {
+            if !matches!(ty, Type::Bool | Type::Int(_)) {
+                self.error(
+                    Diagnostic::error(format!("`{}` cannot be used as a condition", ty.name()))
+                        .with_span(cond.span),
+                );
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: &Type, found: &Type, span: Span) -> bool {
+        if expected == found {
+            return true;
+        }
+        self.error(
+            Diagnostic::error("mismatched types")
+                .with_span(span)
+                .with_label(format!(
+                    "expected `{}`, found `{}`",
+                    expected.name(),
+                    found.name()
+                )),
+        );
+        false
+    }
+
+    fn expect_int(&mut self, ty: &Type, span: Span) -> bool {
+        if matches!(ty, Type::Int(_)) {
+            return true;
+        }
+        self.error(
+            Diagnostic::error("mismatched types")
+                .with_span(span)
+                .with_label(format!("expected an integer, found `{}`", ty.name())),
+        );
+        false
+    }
+
+    /// Types both operands of a binary operator, in the same order as codegen so
+    /// that an untyped operand takes the type of the other one.
+    fn operands(&mut self, a: &Expr, b: &Expr, expected: Option<&Type>) -> Option<Type> {
+        let (first, second) = if is_untyped(&a.node) && !is_untyped(&b.node) {
+            (b, a)
+        } else {
+            (a, b)
+        };
+        let ty = self.expr(first, expected)?;
+        let other = self.expr(second, Some(&ty))?;
+        if self.expect(&ty, &other, second.span) {
+            Some(ty)
+        } else {
+            None
+        }
+    }
+
+    /// Checks that an integer literal, with its sign, fits the type it gets from
+    /// its suffix or else from the context.
+    fn literal(
+        &mut self,
+        value: u128,
+        negative: bool,
+        suffix: Option<IntType>,
+        expected: Option<&Type>,
+        span: Span,
+    ) -> Option<Type> {
+        let ty = match (suffix, expected) {
+            (Some(suffix), _) => suffix,
+            (None, Some(Type::Int(ty))) => *ty,
+            (None, _) => IntType::U8,
+        };
+        let fits = if negative {
+            ty.fits_negated(value)
+        } else {
+            ty.fits(value)
+        };
+        if !fits {
+            let min = if ty.signed() {
+                format!("-{}", ty.max() + 1)
+            } else {
+                String::from("0")
+            };
+            let literal = format!("{}{}", if negative { "-" } else { "" }, value);
+            let mut error = Diagnostic::error(format!("literal out of range for `{}`", ty.name()))
+                .with_span(span)
+                .with_label(format!("the literal `{}` does not fit", literal))
+                .with_note(format!(
+                    "the range of `{}` is `{}..={}`",
+                    ty.name(),
+                    min,
+                    ty.max()
+                ));
+            if suffix.is_none() {
+                error = error.with_note(format!(
+                    "add a suffix to use a wider type, like `{}i32`",
+                    literal
+                ));
+            }
+            self.error(error);
+        }
+        Some(Type::Int(ty))
+    }
+
+    /// Infers the type of an expression, reporting any error in it. Returns
+    /// `None` once an error has been reported, so it is not reported again.
+    fn expr(&mut self, expr: &Expr, expected: Option<&Type>) -> Option<Type> {
+        if let Some((value, suffix)) = negated_literal(&expr.node) {
+            return self.literal(value, true, suffix, expected, expr.span);
+        }
+        match &expr.node {
+            Expr_::Int(value, suffix) => self.literal(*value, false, *suffix, expected, expr.span),
+            Expr_::Bool(_) => Some(Type::Bool),
+            Expr_::Var(name) => match self.variables.get(name) {
+                Some(ty) => Some(ty.clone()),
+                None => {
+                    self.error(
+                        Diagnostic::error(format!("cannot find variable `{}`", name))
+                            .with_span(expr.span)
+                            .with_label("not found in this scope"),
+                    );
+                    None
+                }
+            },
+            Expr_::Add(a, b)
+            | Expr_::Sub(a, b)
+            | Expr_::Mul(a, b)
+            | Expr_::Div(a, b)
+            | Expr_::Mod(a, b) => {
+                let ty = self.operands(a, b, expected)?;
+                if self.expect_int(&ty, expr.span) {
+                    Some(ty)
+                } else {
+                    None
+                }
+            }
+            Expr_::Eq(a, b) | Expr_::NEq(a, b) => {
+                self.operands(a, b, None)?;
+                Some(Type::Bool)
+            }
+            Expr_::Gt(a, b) | Expr_::Lt(a, b) | Expr_::EGt(a, b) | Expr_::ELt(a, b) => {
+                let ty = self.operands(a, b, None)?;
+                if self.expect_int(&ty, expr.span) {
+                    Some(Type::Bool)
+                } else {
+                    None
+                }
+            }
+            Expr_::LNot(a) => {
+                self.condition(a);
+                Some(Type::Bool)
+            }
+            Expr_::LAnd(a, b) | Expr_::LOr(a, b) => {
+                self.condition(a);
+                self.condition(b);
+                Some(Type::Bool)
+            }
+            Expr_::Assign(name, value) => {
+                match self.expr(value, None) {
+                    Some(Type::Void) => self.error(
+                        Diagnostic::error(format!("`{}` cannot hold a value of type `!`", name))
+                            .with_span(value.span)
+                            .with_label("this returns nothing"),
+                    ),
+                    Some(ty) => {
+                        self.variables.insert(name.clone(), ty);
+                    }
+                    None => {}
+                }
+                Some(Type::Void)
+            }
+            Expr_::ReAssign(name, value) => {
+                let ty = match self.variables.get(name) {
+                    Some(ty) => ty.clone(),
+                    None => {
+                        self.error(
+                            Diagnostic::error(format!("cannot find variable `{}`", name))
+                                .with_span(expr.span)
+                                .with_label("declare it with `var` first"),
+                        );
+                        return None;
+                    }
+                };
+                if let Some(found) = self.expr(value, Some(&ty)) {
+                    self.expect(&ty, &found, value.span);
+                }
+                Some(Type::Void)
+            }
+            Expr_::FunctionCall(name, args) => self.call(name, args, expr.span),
+            _ => {
+                self.error(
+                    Diagnostic::error("this expression is not supported yet").with_span(expr.span),
+                );
+                None
+            }
+        }
+    }
+
+    /// Checks the arguments of a call, returning what the function returns.
+    fn call(&mut self, name: &str, args: &[Expr], span: Span) -> Option<Type> {
+        let sig = match self.functions.get(name) {
+            Some(sig) => sig.clone(),
+            None => {
+                self.error(
+                    Diagnostic::error(format!("cannot find function `{}`", name))
+                        .with_span(span)
+                        .with_label("not declared"),
+                );
+                return None;
+            }
+        };
+        if args.len() != sig.params.len() {
+            self.error(
+                Diagnostic::error(format!(
+                    "`{}` takes {} argument(s) but {} were supplied",
+                    name,
+                    sig.params.len(),
+                    args.len()
+                ))
+                .with_span(span),
+            );
+            return None;
+        }
+        for (arg, ty) in args.iter().zip(&sig.params) {
+            if let Some(found) = self.expr(arg, Some(ty)) {
+                self.expect(ty, &found, arg.span);
+            }
+        }
+        Some(sig.ret)
+    }
+}
+
+/// The value and suffix of a negated integer literal like `-128i8`, which is
+/// parsed as `0 - 128i8`.
+fn negated_literal(expr: &Expr_) -> Option<(u128, Option<IntType>)> {
+    match expr {
+        Expr_::Sub(zero, literal) if matches!(zero.node, Expr_::Int(0, None)) => {
+            match literal.node {
+                Expr_::Int(value, suffix) => Some((value, suffix)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Whether a body element is a statement rather than a value to return.
+fn is_statement(expr: &Expr_) -> bool {
+    matches!(
+        expr,
+        Expr_::Assign(..)
+            | Expr_::ReAssign(..)
+            | Expr_::IfElse(..)
+            | Expr_::ForLoop(..)
+            | Expr_::While(..)
+            | Expr_::Loop(..)
+            | Expr_::CallStatement(..)
+            | Expr_::Break(_)
+            | Expr_::Continue(_)
+            | Expr_::Pass
+    )
+}
+
+/// The statements of a block up to the first `return`, `break` or `continue`.
+fn reachable(stmts: &[Expr]) -> &[Expr] {
+    let end = stmts
+        .iter()
+        .position(|stmt| {
+            matches!(stmt.node, Expr_::Break(_) | Expr_::Continue(_)) || !is_statement(&stmt.node)
+        })
+        .map_or(stmts.len(), |i| i + 1);
+    &stmts[..end]
+}
+
+/// Whether every path through `stmts` ends in a `return`.
+fn returns(stmts: &[Expr]) -> bool {
+    match reachable(stmts).last().map(|stmt| &stmt.node) {
+        Some(Expr_::IfElse(_, if_b, else_b)) => returns(if_b) && returns(else_b),
+        // a `loop` is only left through a `break`
+        Some(Expr_::Loop(label, block)) => !breaks(block, label, true),
+        Some(stmt) => !is_statement(stmt),
+        None => false,
+    }
+}
+
+/// Whether `stmts` contain a `break` out of the loop labelled `label` whose body
+/// they are, `innermost` while no other loop is in between.
+fn breaks(stmts: &[Expr], label: &Option<String>, innermost: bool) -> bool {
+    reachable(stmts).iter().any(|stmt| match &stmt.node {
+        Expr_::Break(None) => innermost,
+        Expr_::Break(target) => target == label,
+        Expr_::IfElse(_, if_b, else_b) => {
+            breaks(if_b, label, innermost) || breaks(else_b, label, innermost)
+        }
+        Expr_::ForLoop(_, _, _, _, block) | Expr_::While(_, _, block) | Expr_::Loop(_, block) => {
+            breaks(block, label, false)
+        }
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser;
+
+    /// The messages of the errors in `src`, which must parse.
+    fn errors(src: &str) -> Vec<String> {
+        let program = parser::parse(Lexer::new(src)).expect("the test program parses");
+        check(&program)
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect()
+    }
+
+    fn assert_ok(src: &str) {
+        assert_eq!(errors(src), Vec::<String>::new());
+    }
+
+    #[test]
+    fn negative_literals_use_their_sign() {
+        assert_ok(
+            "declare main = i32;
+             define main = {
+                 var a = -128i8;
+                 var b = 0 - 3i32;
+                 return -2147483648i32;
+             };",
+        );
+        assert_eq!(
+            errors(
+                "declare main = i32;
+                 define main = { var a = -129i8; var b = -1u8; return 0i32; };"
+            ),
+            [
+                "literal out of range for `i8`",
+                "literal out of range for `u8`"
+            ]
+        );
+    }
+
+    #[test]
+    fn every_path_must_return() {
+        assert_ok(
+            "declare sign = i32 -> i32;
+             define sign x = {
+                 if x < 0i32 { return -1i32; } else if x == 0i32 { return 0i32; } else { return 1i32; }
+             };",
+        );
+        assert_eq!(
+            errors(
+                "declare f = i32 -> i32;
+                 define f x = { if x < 0i32 { return 1i32; } };"
+            ),
+            ["`f` may not return a value"]
+        );
+    }
+
+    #[test]
+    fn calls_returning_nothing_are_statements() {
+        assert_ok(
+            "declare extern \"C\" exit = i32 -> !;
+             declare extern \"C\" putchar = i32 -> i32;
+             declare stop = i32 -> !;
+             define stop code = exit(code);
+             declare main = i32;
+             define main = { putchar(65i32); stop(1i32); return 0i32; };",
+        );
+        assert_eq!(
+            errors(
+                "declare extern \"C\" exit = i32 -> !;
+                 declare main = i32;
+                 define main = { var x = exit(1i32); return 0i32; };"
+            )
+            .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn declared_functions_must_be_defined() {
+        assert_eq!(
+            errors(
+                "declare helper = ! -> i32;
+                 declare extern \"C\" abs = i32 -> i32;
+                 declare main = ! -> i32;
+                 define main = helper();"
+            ),
+            ["`helper` is declared but never defined"]
+        );
+        assert_eq!(
+            errors(
+                "declare extern \"C\" abs = i32 -> i32;
+                 define abs x = x;"
+            ),
+            ["`abs` is declared `extern` and cannot be defined"]
+        );
+    }
+
+    #[test]
+    fn loops_return_unless_they_break() {
+        assert_ok(
+            "declare f = i32;
+             define f = { loop { loop { break; } return 1i32; } };",
+        );
+        assert_eq!(
+            errors(
+                "declare f = i32;
+                 define f = { 'outer: loop { loop { break 'outer; } } };"
+            ),
+            ["`f` may not return a value"]
+        );
+        assert_eq!(
+            errors(
+                "declare f = i32;
+                 define f = { loop { if true { break; } } };"
+            ),
+            ["`f` may not return a value"]
+        );
+    }
+
+    #[test]
+    fn variables_are_scoped_to_their_block() {
+        assert_ok(
+            "declare main = i32;
+             define main = { var y = 1i32; while false { var y = 2i32; } return y; };",
+        );
+        assert_ok(
+            "declare main = i32;
+             define main = { for var i = 0i32; i < 3i32; mutate i = i + 1i32; { var j = i; } var i = true; return 0i32; };",
+        );
+        assert_eq!(
+            errors(
+                "declare f = bool -> i32;
+                 define f c = { if c { var y = 4i32; } return y; };"
+            ),
+            ["cannot find variable `y`"]
+        );
+    }
+
+    #[test]
+    fn code_after_a_return_is_not_checked() {
+        // codegen skips the second `var y`, so it must not change the type of `y`
+        assert_eq!(
+            errors(
+                "declare main = i32;
+                 define main = { var y = 0; if true { return 1i32; var y = 2i32; } return y; };"
+            ),
+            ["mismatched types"]
+        );
+        assert_ok(
+            "declare f = i32;
+             define f = { loop { return 1i32; break; } };",
+        );
+    }
+
+    #[test]
+    fn break_and_continue_need_a_loop() {
+        assert_eq!(
+            errors(
+                "declare main = i32;
+                 define main = {
+                     if true { break; }
+                     'a: loop { continue 'b; }
+                     while true { continue 'a; }
+                     return 0i32;
+                 };"
+            ),
+            [
+                "`break` outside of a loop",
+                "use of undeclared label `'b`",
+                "use of undeclared label `'a`",
+            ]
+        );
+    }
+}
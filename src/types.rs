@@ -0,0 +1,134 @@
+use crate::parser::Expr_;
+
+/// The type of a value, a function parameter or a function's return.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Type {
+    Int(IntType),
+    Bool,
+    /// `!`, the return type of functions that return nothing.
+    Void,
+    /// A name that is not a builtin type, reported by the type checker.
+    Named(String),
+}
+
+impl Type {
+    /// Resolves a type name, with `byte` as an alias for `u8`.
+    pub fn from_name(name: &str) -> Type {
+        match name {
+            "byte" => Type::Int(IntType::U8),
+            "bool" => Type::Bool,
+            "void" => Type::Void,
+            name => IntType::from_name(name)
+                .map(Type::Int)
+                .unwrap_or_else(|| Type::Named(name.to_owned())),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Type::Int(ty) => ty.name(),
+            Type::Bool => "bool",
+            Type::Void => "!",
+            Type::Named(name) => name,
+        }
+    }
+
+    pub fn is_signed(&self) -> bool {
+        matches!(self, Type::Int(ty) if ty.signed())
+    }
+}
+
+/// The fixed-width integer types, as named by literal suffixes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IntType {
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl IntType {
+    pub const ALL: [IntType; 8] = [
+        IntType::I8,
+        IntType::I16,
+        IntType::I32,
+        IntType::I64,
+        IntType::U8,
+        IntType::U16,
+        IntType::U32,
+        IntType::U64,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            IntType::I8 => "i8",
+            IntType::I16 => "i16",
+            IntType::I32 => "i32",
+            IntType::I64 => "i64",
+            IntType::U8 => "u8",
+            IntType::U16 => "u16",
+            IntType::U32 => "u32",
+            IntType::U64 => "u64",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<IntType> {
+        IntType::ALL.into_iter().find(|ty| ty.name() == name)
+    }
+
+    pub fn bits(self) -> u32 {
+        match self {
+            IntType::I8 | IntType::U8 => 8,
+            IntType::I16 | IntType::U16 => 16,
+            IntType::I32 | IntType::U32 => 32,
+            IntType::I64 | IntType::U64 => 64,
+        }
+    }
+
+    pub fn signed(self) -> bool {
+        matches!(
+            self,
+            IntType::I8 | IntType::I16 | IntType::I32 | IntType::I64
+        )
+    }
+
+    /// The largest value a literal of this type may have.
+    pub fn max(self) -> u128 {
+        if self.signed() {
+            (1 << (self.bits() - 1)) - 1
+        } else {
+            (1 << self.bits()) - 1
+        }
+    }
+
+    pub fn fits(self, value: u128) -> bool {
+        value <= self.max()
+    }
+
+    /// Whether `-value` is a value of this type.
+    pub fn fits_negated(self, value: u128) -> bool {
+        if self.signed() {
+            value <= self.max() + 1
+        } else {
+            value == 0
+        }
+    }
+}
+
+/// Whether the type of `expr` comes only from its context, as for an
+/// unsuffixed integer literal.
+pub fn is_untyped(expr: &Expr_) -> bool {
+    match expr {
+        Expr_::Int(_, suffix) => suffix.is_none(),
+        Expr_::Add(a, b)
+        | Expr_::Sub(a, b)
+        | Expr_::Mul(a, b)
+        | Expr_::Div(a, b)
+        | Expr_::Mod(a, b) => is_untyped(&a.node) && is_untyped(&b.node),
+        _ => false,
+    }
+}